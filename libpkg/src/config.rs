@@ -0,0 +1,181 @@
+use crate::{
+    error::{Context, PackageManagerError},
+    generations::GenerationId,
+};
+use prelude::logger::{Log, make_fatal};
+use serde::{Deserialize, de::DeserializeOwned};
+use std::{path::Path, time::Duration};
+use tl::{Source, parser::parse, runtime::Scope};
+
+/// Retention policy for generations, read from `config/system/gc.tl`.
+/// A generation is kept if any of the rules match it, if no rules are set every generation is kept.
+#[derive(Debug, Default, Deserialize)]
+pub struct GcPolicy {
+    /// Keep the last N generations.
+    #[serde(default)]
+    pub keep_last: Option<usize>,
+    /// Keep generations newer than the given duration, for example `"12h"`, `"30d"` or `"2w"`.
+    #[serde(default, with = "duration")]
+    pub keep_newer_than: Option<Duration>,
+    /// Generations that are always kept.
+    #[serde(default)]
+    pub pinned: Vec<GenerationId>,
+}
+
+impl GcPolicy {
+    pub fn is_empty(&self) -> bool {
+        self.keep_last.is_none() && self.keep_newer_than.is_none() && self.pinned.is_empty()
+    }
+}
+
+impl super::PackageManager {
+    /// Read the generation retention policy, the default policy keeps every generation.
+    pub fn gc_policy(&self) -> Result<GcPolicy, PackageManagerError> {
+        let path = self.config().join("system/gc.tl");
+
+        if !path.exists() {
+            return Ok(GcPolicy::default());
+        }
+
+        eval_config(path)
+    }
+}
+
+/// Evaluate a config file and deserialize it into the given type.
+pub(crate) fn eval_config<T: DeserializeOwned>(path: impl AsRef<Path>) -> Result<T, PackageManagerError> {
+    let source = Source::from_path(path.as_ref()).context(format!("eval_config: read config file '{}'", path.as_ref().display()))?;
+    let ast = parse(&source).map_err(|err| PackageManagerError::ConfigEval(Box::new(Log::from(*err))))?;
+    let value = Scope::new(source, ast).eval().map_err(|err| PackageManagerError::ConfigEval(Box::new(Log::from(*err))))?;
+
+    T::deserialize(value).map_err(|err| PackageManagerError::ConfigEval(Box::new(make_fatal!("Could not deserialize value: {err}"))))
+}
+
+/// Parse a duration such as `"90s"`, `"30m"`, `"12h"`, `"30d"` or `"2w"`.
+pub(crate) fn parse_duration(input: &str) -> Option<Duration> {
+    let (amount, unit) = split_unit(input)?;
+
+    let secs = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 60 * 60 * 24,
+        "w" => 60 * 60 * 24 * 7,
+        _ => return None,
+    };
+
+    Some(Duration::from_secs(amount.checked_mul(secs)?))
+}
+
+/// Parse a size in bytes such as `"512"`, `"64K"`, `"512M"` or `"4G"`.
+pub(crate) fn parse_size(input: &str) -> Option<u64> {
+    let (amount, unit) = split_unit(input)?;
+
+    let multiplier = match unit {
+        "" | "B" => 1,
+        "K" => 1 << 10,
+        "M" => 1 << 20,
+        "G" => 1 << 30,
+        "T" => 1 << 40,
+        _ => return None,
+    };
+
+    amount.checked_mul(multiplier)
+}
+
+fn split_unit(input: &str) -> Option<(u64, &str)> {
+    let input = input.trim();
+    let split = input.find(|c: char| !c.is_ascii_digit()).unwrap_or(input.len());
+    let (amount, unit) = input.split_at(split);
+
+    Some((amount.parse().ok()?, unit.trim()))
+}
+
+/// (De)serialize an optional duration as a string such as `"30d"`.
+pub(crate) mod duration {
+    use serde::{Deserialize, Deserializer, Serialize, Serializer, de::Error};
+    use std::time::Duration;
+
+    pub fn serialize<S: Serializer>(value: &Option<Duration>, serializer: S) -> Result<S::Ok, S::Error> {
+        value.map(|duration| format!("{}s", duration.as_secs())).serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Duration>, D::Error> {
+        let Some(string) = Option::<String>::deserialize(deserializer)? else {
+            return Ok(None);
+        };
+
+        super::parse_duration(&string).map(Some).ok_or_else(|| D::Error::custom(format!("invalid duration \"{string}\"")))
+    }
+}
+
+/// (De)serialize an optional size in bytes as a string such as `"4G"`.
+pub(crate) mod size {
+    use serde::{Deserialize, Deserializer, Serialize, Serializer, de::Error};
+
+    pub fn serialize<S: Serializer>(value: &Option<u64>, serializer: S) -> Result<S::Ok, S::Error> {
+        value.map(|bytes| bytes.to_string()).serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<u64>, D::Error> {
+        let Some(string) = Option::<String>::deserialize(deserializer)? else {
+            return Ok(None);
+        };
+
+        super::parse_size(&string).map(Some).ok_or_else(|| D::Error::custom(format!("invalid size \"{string}\"")))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn durations_need_a_known_unit() {
+        let cases = [
+            ("90s", Some(Duration::from_secs(90))),
+            ("30m", Some(Duration::from_secs(30 * 60))),
+            ("12h", Some(Duration::from_secs(12 * 60 * 60))),
+            (" 30d ", Some(Duration::from_secs(30 * 24 * 60 * 60))),
+            ("2 w", Some(Duration::from_secs(2 * 7 * 24 * 60 * 60))),
+            ("0s", Some(Duration::ZERO)),
+            ("90", None),
+            ("", None),
+            ("s", None),
+            ("10y", None),
+            ("10ms", None),
+            ("-1s", None),
+            ("1.5h", None),
+            ("18446744073709551615w", None),
+            ("18446744073709551616s", None),
+        ];
+
+        for (input, expected) in cases {
+            assert_eq!(parse_duration(input), expected, "{input:?}");
+        }
+    }
+
+    #[test]
+    fn sizes_are_bytes_or_binary_units() {
+        let cases = [
+            ("512", Some(512)),
+            ("512B", Some(512)),
+            ("64K", Some(64 << 10)),
+            ("512M", Some(512 << 20)),
+            (" 4 G ", Some(4 << 30)),
+            ("2T", Some(2 << 40)),
+            ("0", Some(0)),
+            ("", None),
+            ("K", None),
+            ("4KB", None),
+            ("4k", None),
+            ("4P", None),
+            ("-1", None),
+            ("18446744073709551615K", None),
+            ("18446744073709551616", None),
+        ];
+
+        for (input, expected) in cases {
+            assert_eq!(parse_size(input), expected, "{input:?}");
+        }
+    }
+}
@@ -0,0 +1,111 @@
+use prelude::logger::{make_fatal, Log};
+use std::{fmt::Write, fs, path::Path};
+use tl::Source;
+
+/// A position in a source file, both starting at 1.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) struct Location {
+    pub line: usize,
+    pub column: usize,
+}
+
+/// The text of a `.tl` file, kept around to point evaluation errors at the expressions they are about.
+#[derive(Debug, Clone)]
+pub(crate) struct SourceText {
+    name: String,
+    lines: Vec<String>,
+}
+
+impl SourceText {
+    /// Read the text of a source, only sources read from a file can be pointed into.
+    pub fn read(source: &Source) -> Option<Self> {
+        Self::open(source.path.as_ref()?)
+    }
+
+    /// Read the text of a file.
+    pub fn open(path: &Path) -> Option<Self> {
+        let text = fs::read_to_string(path).ok()?;
+
+        Some(Self {
+            name: path.display().to_string(),
+            lines: text.lines().map(ToOwned::to_owned).collect(),
+        })
+    }
+
+    /// Find the first occurrence of a pattern on or after the line of a location.
+    pub fn find_from(&self, start: Location, pattern: &str) -> Option<Location> {
+        self.lines.iter().enumerate().skip(start.line - 1).find_map(|(index, line)| {
+            Some(Location {
+                line: index + 1,
+                column: line.find(pattern)? + 1,
+            })
+        })
+    }
+
+    /// Find the call of a function whose object sets the given id, or the first call of the function if that can't be told.
+    /// The call that sets an id is the last one that starts before the id is set.
+    pub fn find_call(&self, function: &str, id: Option<&str>) -> Option<Location> {
+        let pattern = format!("{function}(");
+        let calls = self
+            .lines
+            .iter()
+            .enumerate()
+            .flat_map(|(index, line)| {
+                line.match_indices(&pattern)
+                    .filter(|(column, _)| !line[..*column].ends_with(|c: char| c.is_alphanumeric() || c == '_'))
+                    .map(move |(column, _)| Location { line: index + 1, column: column + 1 })
+            })
+            .collect::<Vec<_>>();
+
+        let first = Location { line: 1, column: 1 };
+        let set = id.and_then(|id| self.find_from(first, &format!("id = \"{id}\"")));
+
+        match set {
+            Some(set) => calls.iter().rev().find(|call| **call <= set).or(calls.first()).copied(),
+            None => calls.first().copied(),
+        }
+    }
+
+    /// Find the start of the expression that the file evaluates to.
+    /// This is the last line that starts a top-level expression, bindings and closing delimiters don't count.
+    pub fn result(&self) -> Option<Location> {
+        self.lines
+            .iter()
+            .enumerate()
+            .rev()
+            .find(|(_, line)| line.starts_with(|c: char| !c.is_whitespace() && !matches!(c, '}' | ']' | ')')) && !line.starts_with("let "))
+            .map(|(index, _)| Location { line: index + 1, column: 1 })
+    }
+
+    /// Render a message pointing at a location, along with an optional suggestion.
+    pub fn render(&self, location: Location, message: &str, help: Option<&str>) -> String {
+        let number = location.line.to_string();
+        let gutter = " ".repeat(number.len());
+        let line = self.lines.get(location.line - 1).map(String::as_str).unwrap_or_default();
+
+        let mut rendered = format!("{message}\n{gutter}--> {}:{}:{}\n{gutter} |\n{number} | {line}\n", self.name, location.line, location.column);
+        let _ = write!(rendered, "{gutter} | {}^", " ".repeat(location.column - 1));
+
+        if let Some(help) = help {
+            let _ = write!(rendered, "\n{gutter} = help: {help}");
+        }
+
+        rendered
+    }
+}
+
+/// Render a message, pointing at a location in the source if it is known.
+pub(crate) fn render(text: Option<&SourceText>, location: Option<Location>, message: &str, help: Option<&str>) -> String {
+    match (text, location) {
+        (Some(text), Some(location)) => text.render(location, message, help),
+        _ => match help {
+            Some(help) => format!("{message}\n  = help: {help}"),
+            None => message.to_owned(),
+        },
+    }
+}
+
+/// Create an evaluation error, pointing at a location in the source if it is known.
+pub(crate) fn diagnostic(text: Option<&SourceText>, location: Option<Location>, message: &str, help: Option<&str>) -> Box<Log> {
+    Box::new(make_fatal!("{}", render(text, location, message, help)))
+}
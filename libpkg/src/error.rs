@@ -2,7 +2,7 @@ use prelude::{
     logger::Log,
     thiserror::{self, Error},
 };
-use std::{io, num::ParseIntError};
+use std::{io, num::ParseIntError, path::PathBuf, time::Duration};
 
 pub type Result<T> = core::result::Result<T, PackageManagerError>;
 
@@ -34,6 +34,25 @@ impl<T> Context<T, fs_extra::error::Result<T>> for fs_extra::error::Result<T> {
     }
 }
 
+#[cfg(feature = "repositories")]
+impl<T> Context<T, reqwest::Error> for reqwest::Result<T> {
+    fn context(self, context: impl Into<String>) -> Result<T> {
+        self.map_err(|e| PackageManagerError::http(context, e))
+    }
+}
+
+impl<T> Context<T, bincode::Error> for bincode::Result<T> {
+    fn context(self, context: impl Into<String>) -> Result<T> {
+        self.map_err(|e| PackageManagerError::bincode(context, e))
+    }
+}
+
+impl<T> Context<T, serde_json::Error> for serde_json::Result<T> {
+    fn context(self, context: impl Into<String>) -> Result<T> {
+        self.map_err(|e| PackageManagerError::json(context, e))
+    }
+}
+
 #[derive(Debug, Error)]
 pub enum PackageManagerError {
     #[error("The given root path does not exist")]
@@ -42,12 +61,62 @@ pub enum PackageManagerError {
     PackageNotInstalled,
     #[error("The package is already installed")]
     PackageAlreadyInstalled,
+    #[error("No build log was found for the package")]
+    BuildLogNotFound,
+    #[error("The dependency \"{0}\" is not installed")]
+    MissingDependency(String),
+    #[error("The archive is invalid: {0}")]
+    InvalidArchive(String),
+    #[error("The archive's outputs have the hash {actual} but {expected} was expected")]
+    ArchiveHashMismatch { expected: String, actual: String },
+    #[error("The binary cache \"{0}\" is only supported with the repositories feature")]
+    UnsupportedCache(String),
+    #[error("The fetched output has the hash {actual} but {expected} was expected")]
+    FetchHashMismatch { expected: String, actual: String },
+    #[error("The source \"{0}\" can't be fetched yet, only local paths are supported")]
+    UnsupportedSource(String),
     #[error("The package uses a local source but was fetched from a remote location")]
     LocalPathOnRemotePackage,
+    #[error("The source \"{}\" does not exist", .0.display())]
+    MissingSource(PathBuf),
+    #[error("The package is still required by: {}", .0.join(", "))]
+    PackageRequired(Vec<String>),
+    #[error("Generation {0} is in use and cannot be deleted")]
+    GenerationInUse(u32),
     #[error("Error setting user id")]
     SetUID,
+    #[error("The build user \"{0}\" does not exist")]
+    BuildUserNotFound(String),
+    #[error("The build output is missing expected files: {}", .0.iter().map(|path| path.display().to_string()).collect::<Vec<_>>().join(", "))]
+    MissingOutput(Vec<PathBuf>),
+    #[error("The dependencies \"{0}\" and \"{1}\" would both be exposed as ${2}")]
+    DependencyVariableCollision(String, String, String),
+    #[error("No nushell executable was found for the debug shell, set `shell` in the sandbox config")]
+    NushellNotFound,
+    #[error("The script failed: {0}")]
+    ScriptFailed(String),
+    #[error("The build exited with status {0}")]
+    BuildFailed(i32),
+    #[error("The output directory \"{}\" is not empty", .0.display())]
+    OutputNotEmpty(PathBuf),
+    #[error("The build was killed by {0}")]
+    BuildKilled(nix::sys::signal::Signal),
+    #[error("The build timed out after {0:?}")]
+    BuildTimedOut(Duration),
+    #[error("The build exceeded its CPU time limit of {0:?}")]
+    CpuLimitExceeded(Duration),
+    #[error("The build exceeded its memory limit of {0} bytes")]
+    MemoryLimitExceeded(u64),
+    #[error("The build exceeded its limit of {0} processes")]
+    ProcessLimitExceeded(u64),
+    #[error("The build exceeded its output size limit of {0} bytes")]
+    OutputLimitExceeded(u64),
+    #[error("The metadata of the store item \"{}\" has the unsupported format version {1}", .0.display())]
+    UnsupportedMetadataFormat(PathBuf, u32),
     #[error("Error evaluating package: {0}")]
     PackageEval(Box<Log>),
+    #[error("Error evaluating config: {0}")]
+    ConfigEval(Box<Log>),
 
     #[error("Error Parsing Int: {0}")]
     ParseInt(#[from] ParseIntError),
@@ -76,6 +145,25 @@ pub enum PackageManagerError {
         #[source]
         source: fs_extra::error::Error,
     },
+    #[cfg(feature = "repositories")]
+    #[error("{context}: {source}")]
+    Http {
+        context: String,
+        #[source]
+        source: reqwest::Error,
+    },
+    #[error("{context}: {source}")]
+    Bincode {
+        context: String,
+        #[source]
+        source: bincode::Error,
+    },
+    #[error("{context}: {source}")]
+    Json {
+        context: String,
+        #[source]
+        source: serde_json::Error,
+    },
 }
 
 impl PackageManagerError {
@@ -94,6 +182,19 @@ impl PackageManagerError {
     pub fn fs(context: impl Into<String>, err: fs_extra::error::Error) -> Self {
         Self::FS { context: context.into(), source: err }
     }
+
+    #[cfg(feature = "repositories")]
+    pub fn http(context: impl Into<String>, err: reqwest::Error) -> Self {
+        Self::Http { context: context.into(), source: err }
+    }
+
+    pub fn bincode(context: impl Into<String>, err: bincode::Error) -> Self {
+        Self::Bincode { context: context.into(), source: err }
+    }
+
+    pub fn json(context: impl Into<String>, err: serde_json::Error) -> Self {
+        Self::Json { context: context.into(), source: err }
+    }
 }
 
 impl From<Box<Log>> for PackageManagerError {
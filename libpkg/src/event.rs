@@ -1,14 +1,33 @@
-use crate::error::PackageManagerError;
+use crate::{error::PackageManagerError, store::Transaction};
+use std::path::PathBuf;
 
 #[derive(Debug)]
 pub enum Event {
-    /// Waiting for the lock to be removed from the store.
-    AwaitingUnlock,
-    /// When the store has been unlocked.
+    /// Waiting for another process to release the store's lock, with the PID of the last writer if known.
+    AwaitingUnlock(Option<u32>),
+    /// When the store's lock has been acquired.
     Unlocked,
+    /// An interrupted transaction found in the journal was recovered.
+    RecoveredTransaction(Transaction),
     /// Creating the directory for the package to be installed in the store.
     AllocatingInStore,
     /// (number of bytes copied, number of bytes to copy in total)
     CopySrcProgress(u64, u64),
+    /// A prebuilt archive of the package was found in the given binary cache.
+    Substituting(String),
+    /// Installing from the given binary cache failed with the given error, falling back to the next cache or to building from source.
+    SubstitutionFailed(String, String),
+    /// A line written to stdout or stderr by a build stage.
+    BuildLog(String),
+    /// A build stage failed and an interactive shell is being opened in its sandbox, the build continues once it exits.
+    DebugShell,
+    /// The build's scratch directory was left around at the given path for debugging.
+    ScratchKept(PathBuf),
+    /// A package was built without installing it, its output is at the given path.
+    Built(PathBuf),
+    /// A path in the output of a rebuilt package differs from its store item, relative to the item.
+    OutputDiffers(PathBuf),
+    /// A package that depends on the one being removed is also being removed, (id, version).
+    RemovingDependent(String, String),
     Error(PackageManagerError),
 }
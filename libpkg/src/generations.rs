@@ -1,7 +1,19 @@
-use crate::error::{Context, PackageManagerError};
-use std::{env, fs, os::unix::fs::symlink, path::Path};
+use crate::{
+    config::GcPolicy,
+    error::{Context, PackageManagerError},
+    store::StoreItem,
+};
+use std::{
+    fs, io,
+    os::unix::fs::symlink,
+    path::Path,
+    time::{SystemTime, UNIX_EPOCH},
+};
 
-type GenerationId = u32;
+pub type GenerationId = u32;
+
+/// Name of the file inside a generation listing the store items it references.
+const MANIFEST_FILE: &str = "manifest";
 
 #[derive(Debug)]
 pub struct Generation {
@@ -9,6 +21,10 @@ pub struct Generation {
     pub id: GenerationId,
     /// Unix timestamp of the generation's creation date.
     pub created: u64,
+    /// Names of the store items referenced by the generation, in the `<id>-<version>` format.
+    pub packages: Vec<String>,
+    /// The generation predates manifests, so every installed store item is treated as part of it.
+    pub legacy: bool,
 }
 
 impl super::PackageManager {
@@ -17,25 +33,55 @@ impl super::PackageManager {
     }
 
     pub fn set_current_generation(&self, id: GenerationId) -> Result<(), PackageManagerError> {
-        let old_cwd = env::current_dir().context("set_current_generation: read current directory")?;
+        self.point_generation_link("current", id).context("set_current_generation: update the current generation symlink")
+    }
 
-        env::set_current_dir(self.generations()).context("set_current_generation: set current directory to generations")?;
-        symlink(id.to_string(), "current").context("set_current_generation: update the current generation symlink")?;
-        env::set_current_dir(old_cwd).context("set_current_generation: set current directory back to old one")?;
+    /// Mark a generation as the one the bootloader boots, which must be done whenever a bootloader entry is written for it.
+    /// The boot generation is never pruned or deleted, even once it's no longer current.
+    pub fn set_boot_generation(&self, id: GenerationId) -> Result<(), PackageManagerError> {
+        self.point_generation_link("boot", id).context("set_boot_generation: update the boot generation symlink")
+    }
 
-        Ok(())
+    /// Atomically point a symlink in the generations directory at a generation, replacing where it pointed before.
+    fn point_generation_link(&self, name: &str, id: GenerationId) -> io::Result<()> {
+        let temp = self.generations().join(format!(".{name}.tmp"));
+
+        if let Err(err) = fs::remove_file(&temp)
+            && err.kind() != io::ErrorKind::NotFound
+        {
+            return Err(err);
+        }
+
+        symlink(id.to_string(), &temp)?;
+        fs::rename(temp, self.generations().join(name))
     }
 
     pub fn read_generation(&self, path: impl AsRef<Path>) -> Result<Generation, PackageManagerError> {
         let path = path.as_ref();
+        let path = path.file_name().map(Path::new).unwrap_or(path);
 
         let id = path.display().to_string().parse::<u32>()?;
-        let created = fs::read_to_string(self.generations().join(path).join("created"))
-            .context("read_generation: read creation date for generation")?
-            .trim()
-            .parse::<u64>()?;
+        let path = self.generations().join(path);
+
+        // Generations made before creation dates were recorded fall back to the modification time of their directory.
+        let created = match fs::read_to_string(path.join("created")) {
+            Ok(created) => created.trim().parse::<u64>()?,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => fs::metadata(&path)
+                .and_then(|metadata| metadata.modified())
+                .context("read_generation: read the modification time of a generation without a creation date")?
+                .duration_since(UNIX_EPOCH)
+                .map(|time| time.as_secs())
+                .unwrap_or_default(),
+            Err(err) => return Err(PackageManagerError::io("read_generation: read creation date for generation", err)),
+        };
+
+        let (packages, legacy) = match fs::read_to_string(path.join(MANIFEST_FILE)) {
+            Ok(manifest) => (manifest.lines().filter(|s| !s.trim().is_empty()).map(ToString::to_string).collect(), false),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => (Vec::new(), true),
+            Err(err) => return Err(PackageManagerError::io("read_generation: read the generation's manifest", err)),
+        };
 
-        Ok(Generation { id, created })
+        Ok(Generation { id, created, packages, legacy })
     }
 
     pub fn current_generation(&self) -> Result<Generation, PackageManagerError> {
@@ -44,6 +90,11 @@ impl super::PackageManager {
         self.read_generation(current)
     }
 
+    /// The generation the bootloader points at, tracked by the `boot` symlink in the generations directory.
+    pub fn boot_generation(&self) -> Option<GenerationId> {
+        fs::read_link(self.generations().join("boot")).ok()?.file_name()?.to_str()?.parse().ok()
+    }
+
     pub fn list_generations(&self) -> Result<Vec<Generation>, PackageManagerError> {
         let dirs = fs::read_dir(self.generations())
             .context("list_generations: list the directories in the generations")?
@@ -61,11 +112,189 @@ impl super::PackageManager {
         Ok(generations)
     }
 
+    /// Create the generation after the current one, starting from the current one's manifest.
     pub fn make_generation(&self) -> Result<(), PackageManagerError> {
-        let current_id = self.current_generation()?.id;
+        let current = self.migrated_current_generation()?;
+        let mut generation = self.create_generation_dir(current.id + 1)?;
+        generation.packages = current.packages;
+
+        self.write_generation_manifest(&generation)
+    }
+
+    /// Find the generations that fall outside of the given retention policy.
+    /// The current generation and the one the bootloader points at are never included.
+    pub fn generations_to_prune(&self, policy: &GcPolicy) -> Result<Vec<GenerationId>, PackageManagerError> {
+        if policy.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let current = self.current_generation()?.id;
+        let boot = self.boot_generation();
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|time| time.as_secs()).unwrap_or_default();
+
+        let mut generations = self.list_generations()?;
+        generations.sort_by_key(|generation| std::cmp::Reverse(generation.id));
+
+        Ok(generations
+            .into_iter()
+            .enumerate()
+            .filter(|(index, generation)| {
+                let protected = generation.id == current || Some(generation.id) == boot || policy.pinned.contains(&generation.id);
+                let recent = policy.keep_last.is_some_and(|keep_last| *index < keep_last);
+                let new = policy.keep_newer_than.is_some_and(|max_age| now.saturating_sub(generation.created) < max_age.as_secs());
+
+                !(protected || recent || new)
+            })
+            .map(|(_, generation)| generation.id)
+            .collect())
+    }
+
+    /// Delete a generation, refusing to delete the current generation or the one the bootloader points at.
+    pub fn delete_generation(&self, id: GenerationId) -> Result<(), PackageManagerError> {
+        if id == self.current_generation()?.id || Some(id) == self.boot_generation() {
+            return Err(PackageManagerError::GenerationInUse(id));
+        }
+
+        fs::remove_dir_all(self.generations().join(id.to_string())).context("delete_generation: remove the generation's directory")?;
+
+        Ok(())
+    }
+
+    /// Create the directory of a generation along with its creation date and an empty manifest.
+    pub(crate) fn create_generation_dir(&self, id: GenerationId) -> Result<Generation, PackageManagerError> {
+        let path = self.generations().join(id.to_string());
+        let created = SystemTime::now().duration_since(UNIX_EPOCH).map(|time| time.as_secs()).unwrap_or_default();
+
+        fs::create_dir(&path).context("create_generation_dir: create directory for the new generation")?;
+        fs::write(path.join("created"), created.to_string()).context("create_generation_dir: write creation date")?;
+        fs::write(path.join(MANIFEST_FILE), "").context("create_generation_dir: write empty manifest")?;
+
+        Ok(Generation {
+            id,
+            created,
+            packages: Vec::new(),
+            legacy: false,
+        })
+    }
+
+    /// Add a store item to the manifest of the current generation.
+    pub(crate) fn add_to_current_generation(&self, item: impl AsRef<str>) -> Result<(), PackageManagerError> {
+        let mut current = self.migrated_current_generation()?;
+
+        if !current.packages.iter().any(|package| package == item.as_ref()) {
+            current.packages.push(item.as_ref().to_owned());
+        }
+
+        self.write_generation_manifest(&current)
+    }
+
+    /// Replace the manifest of the current generation.
+    pub(crate) fn set_current_manifest(&self, packages: Vec<String>) -> Result<(), PackageManagerError> {
+        let mut current = self.current_generation()?;
+        current.packages = packages;
+        current.legacy = false;
+
+        self.write_generation_manifest(&current)
+    }
+
+    /// Remove a store item from the manifest of the current generation.
+    pub(crate) fn remove_from_current_generation(&self, item: impl AsRef<str>) -> Result<(), PackageManagerError> {
+        let mut current = self.migrated_current_generation()?;
+        current.packages.retain(|package| package != item.as_ref());
 
-        fs::create_dir(self.generations().join((current_id + 1).to_string())).context("make_generation: create directory for new directory for the new generation")?;
+        self.write_generation_manifest(&current)
+    }
+
+    /// Read the current generation, giving a legacy generation the manifest it implies before it's changed.
+    /// Writing only the changed item would otherwise make every other installed item unreachable.
+    pub(crate) fn migrated_current_generation(&self) -> Result<Generation, PackageManagerError> {
+        let mut current = self.current_generation()?;
+
+        if current.legacy {
+            current.packages = self.store_items()?.into_iter().filter(StoreItem::is_installed).map(|item| item.name()).collect();
+            current.packages.sort();
+            current.legacy = false;
+        }
+
+        Ok(current)
+    }
+
+    fn write_generation_manifest(&self, generation: &Generation) -> Result<(), PackageManagerError> {
+        fs::write(self.generations().join(generation.id.to_string()).join(MANIFEST_FILE), generation.packages.join("\n"))
+            .context("write_generation_manifest: write the generation's manifest")?;
 
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        config::GcPolicy,
+        util::test::{add_item, package, root},
+    };
+    use std::time::Duration;
+
+    #[test]
+    fn new_generations_start_from_the_current_manifest() {
+        let (_root, pm) = root(Some(&["app-1.0.0"]));
+
+        pm.make_generation().unwrap();
+
+        assert_eq!(pm.read_generation("2").unwrap().packages, ["app-1.0.0"]);
+    }
+
+    #[test]
+    fn legacy_generations_are_migrated_before_they_change() {
+        let (_root, pm) = root(None);
+        add_item(&pm, &package("app", "1.0.0", &[]), true);
+        add_item(&pm, &package("old", "1.0.0", &[]), false);
+
+        let current = pm.current_generation().unwrap();
+        assert!(current.legacy && current.packages.is_empty());
+
+        pm.add_to_current_generation("new-1.0.0").unwrap();
+
+        let current = pm.current_generation().unwrap();
+        assert!(!current.legacy);
+        assert_eq!(current.packages, ["app-1.0.0", "new-1.0.0"]);
+    }
+
+    #[test]
+    fn pruning_keeps_protected_and_retained_generations() {
+        let (_root, pm) = root(Some(&[]));
+
+        for id in 2..=6 {
+            pm.create_generation_dir(id).unwrap();
+        }
+
+        pm.set_current_generation(6).unwrap();
+        pm.set_boot_generation(2).unwrap();
+
+        let prune = |keep_last, keep_newer_than, pinned| {
+            let mut ids = pm.generations_to_prune(&GcPolicy { keep_last, keep_newer_than, pinned }).unwrap();
+            ids.sort();
+            ids
+        };
+
+        assert_eq!(prune(None, None, vec![]), Vec::<u32>::new());
+        assert_eq!(prune(Some(2), None, vec![1]), [3, 4]);
+        assert_eq!(prune(Some(0), None, vec![]), [1, 3, 4, 5]);
+        assert_eq!(prune(None, Some(Duration::from_secs(3600)), vec![]), Vec::<u32>::new());
+    }
+
+    #[test]
+    fn the_boot_generation_can_not_be_deleted() {
+        let (_root, pm) = root(Some(&[]));
+        pm.create_generation_dir(2).unwrap();
+        pm.set_current_generation(2).unwrap();
+
+        assert!(pm.delete_generation(2).is_err());
+        pm.set_boot_generation(1).unwrap();
+        assert!(pm.delete_generation(1).is_err());
+
+        pm.set_boot_generation(2).unwrap();
+        pm.delete_generation(1).unwrap();
+        assert_eq!(pm.list_generations().unwrap().len(), 1);
+    }
+}
@@ -0,0 +1,166 @@
+use sha2::{Digest, Sha256};
+use std::{
+    collections::BTreeSet,
+    fs,
+    io::{self, Read},
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+};
+
+/// Prefix of every hash produced by this module, to allow other algorithms later on.
+const ALGORITHM: &str = "sha256";
+
+/// Hash a file or directory tree into a string such as `sha256:<hex>`.
+/// The hash covers relative paths, file types, contents, symlink targets and the executable bit, but not timestamps or ownership.
+pub fn hash_tree(path: impl AsRef<Path>) -> io::Result<String> {
+    hash_tree_without(path, &[])
+}
+
+/// Hash a directory tree like [`hash_tree`], leaving out the given top-level entries.
+pub fn hash_tree_without(path: impl AsRef<Path>, ignored: &[&str]) -> io::Result<String> {
+    let mut hasher = Sha256::new();
+    hash_entry(path.as_ref(), Path::new(""), ignored, &mut hasher)?;
+
+    Ok(format!("{ALGORITHM}:{}", hex(&hasher.finalize())))
+}
+
+/// Hash a byte slice into a string such as `sha256:<hex>`.
+pub fn hash_bytes(bytes: impl AsRef<[u8]>) -> String {
+    format!("{ALGORITHM}:{}", hex(&Sha256::digest(bytes)))
+}
+
+/// List the paths, relative to the roots, of the entries that differ between two trees when hashed like [`hash_tree`].
+/// Directories present on both sides are descended into, anything missing on either side is reported as a difference.
+pub fn diff_trees(a: impl AsRef<Path>, b: impl AsRef<Path>, ignored: &[&str]) -> io::Result<Vec<PathBuf>> {
+    let mut differences = Vec::new();
+    diff_entry(a.as_ref(), b.as_ref(), Path::new(""), ignored, &mut differences)?;
+
+    Ok(differences)
+}
+
+fn diff_entry(a: &Path, b: &Path, relative: &Path, ignored: &[&str], differences: &mut Vec<PathBuf>) -> io::Result<()> {
+    let (a_metadata, b_metadata) = (fs::symlink_metadata(a).ok(), fs::symlink_metadata(b).ok());
+
+    match (a_metadata, b_metadata) {
+        (Some(a_metadata), Some(b_metadata)) if a_metadata.is_dir() && b_metadata.is_dir() => {
+            let mut entries = BTreeSet::new();
+
+            for dir in [a, b] {
+                for entry in fs::read_dir(dir)? {
+                    entries.insert(entry?.file_name());
+                }
+            }
+
+            entries.retain(|name| !ignored.iter().any(|ignored| name == ignored));
+
+            for name in entries {
+                diff_entry(&a.join(&name), &b.join(&name), &relative.join(&name), &[], differences)?;
+            }
+        }
+        (Some(_), Some(_)) if hash_tree(a)? == hash_tree(b)? => {}
+        _ => differences.push(relative.to_path_buf()),
+    }
+
+    Ok(())
+}
+
+fn hash_entry(path: &Path, relative: &Path, ignored: &[&str], hasher: &mut Sha256) -> io::Result<()> {
+    let metadata = fs::symlink_metadata(path)?;
+    let file_type = metadata.file_type();
+
+    hasher.update(relative.as_os_str().as_encoded_bytes());
+    hasher.update([0]);
+
+    if file_type.is_symlink() {
+        hasher.update(b"symlink\0");
+        hasher.update(fs::read_link(path)?.as_os_str().as_encoded_bytes());
+    } else if file_type.is_dir() {
+        hasher.update(b"directory\0");
+
+        let mut entries = fs::read_dir(path)?.map(|entry| entry.map(|entry| entry.file_name())).collect::<io::Result<Vec<_>>>()?;
+        entries.retain(|name| !ignored.iter().any(|ignored| name == ignored));
+        entries.sort();
+
+        for name in entries {
+            hash_entry(&path.join(&name), &relative.join(&name), &[], hasher)?;
+        }
+    } else {
+        hasher.update(if metadata.permissions().mode() & 0o111 != 0 { "executable\0" } else { "regular\0" });
+        hash_contents(path, hasher)?;
+    }
+
+    hasher.update([0]);
+
+    Ok(())
+}
+
+fn hash_contents(path: &Path, hasher: &mut Sha256) -> io::Result<()> {
+    let mut file = fs::File::open(path)?;
+    let mut buffer = [0; 64 * 1024];
+
+    loop {
+        let read = file.read(&mut buffer)?;
+
+        if read == 0 {
+            return Ok(());
+        }
+
+        hasher.update(&buffer[..read]);
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::test::TempDir;
+
+    fn tree(files: &[(&str, &str)]) -> TempDir {
+        let dir = TempDir::new();
+
+        for (path, contents) in files {
+            let path = dir.join(path);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, contents).unwrap();
+        }
+
+        dir
+    }
+
+    #[test]
+    fn identical_trees_have_no_differences() {
+        let files = [("bin/foo", "foo"), ("share/doc/README", "readme")];
+        let (a, b) = (tree(&files), tree(&files));
+
+        assert!(diff_trees(&*a, &*b, &[]).unwrap().is_empty());
+        assert_eq!(hash_tree(&*a).unwrap(), hash_tree(&*b).unwrap());
+    }
+
+    #[test]
+    fn differences_are_reported_at_the_deepest_path() {
+        let a = tree(&[("bin/foo", "foo"), ("bin/bar", "bar"), ("lib/libfoo.so", "lib")]);
+        let b = tree(&[("bin/foo", "changed"), ("bin/bar", "bar"), ("share/man/foo.1", "man")]);
+
+        assert_eq!(diff_trees(&*a, &*b, &[]).unwrap(), [Path::new("bin/foo"), Path::new("lib"), Path::new("share")]);
+    }
+
+    #[test]
+    fn the_executable_bit_is_a_difference() {
+        let (a, b) = (tree(&[("foo", "foo")]), tree(&[("foo", "foo")]));
+        fs::set_permissions(b.join("foo"), fs::Permissions::from_mode(0o755)).unwrap();
+
+        assert_eq!(diff_trees(&*a, &*b, &[]).unwrap(), [Path::new("foo")]);
+    }
+
+    #[test]
+    fn ignored_entries_are_only_skipped_at_the_top_level() {
+        let a = tree(&[("links", "a"), ("bin/links", "a")]);
+        let b = tree(&[("links", "b"), ("bin/links", "b")]);
+
+        assert_eq!(diff_trees(&*a, &*b, &["links"]).unwrap(), [Path::new("bin/links")]);
+        assert_ne!(hash_tree_without(&*a, &["links"]).unwrap(), hash_tree_without(&*b, &["links"]).unwrap());
+    }
+}
@@ -9,17 +9,30 @@ use std::{
 };
 
 // Re-exports
+pub use lint::{Lint, Severity};
+pub use sandbox::{Limits, SandboxConfig};
+pub use store::{ArchiveHeader, BuildOptions, CacheConfig, GcReport, LockMode, RemovePolicy, StoreConfig, StoreItem, StoreLock, Transaction};
 pub use tl::Source;
+pub use trace::TraceEvent;
 
+pub mod config;
 pub mod error;
 pub mod event;
 pub mod generations;
 pub mod package;
 
+mod diagnostic;
+mod hash;
+mod lint;
 mod manager;
+mod overlay;
 mod paths;
+mod sandbox;
+mod stdlib;
 mod store;
+mod trace;
 mod util;
+mod version;
 
 #[derive(Debug)]
 pub struct PackageManager {
@@ -0,0 +1,250 @@
+use crate::{
+    package::{Definition, Group, Member, Package, Src},
+    version::Version,
+};
+use std::fmt::{self, Display};
+
+/// How serious a lint is, errors should block a package from being published.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Severity {
+    Warning,
+    Error,
+}
+
+impl Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Warning => write!(f, "warning"),
+            Self::Error => write!(f, "error"),
+        }
+    }
+}
+
+/// A problem found in a package definition by [`Definition::lint`].
+#[derive(Debug, Clone)]
+pub struct Lint {
+    /// Id of the package or group the lint is about.
+    pub id: String,
+    /// Stable name of the check, such as `missing-description`.
+    pub code: &'static str,
+    pub severity: Severity,
+    /// The field the lint is about.
+    pub field: String,
+    pub message: String,
+}
+
+impl Definition {
+    /// Statically check a package or every package of a group for common mistakes.
+    pub fn lint(&self) -> Vec<Lint> {
+        let mut lints = Vec::new();
+
+        match self {
+            Self::Package(package) => package.lint(&mut lints),
+            Self::Group(group) => group.lint(&mut lints),
+        }
+
+        lints
+    }
+}
+
+impl Group {
+    fn lint(&self, lints: &mut Vec<Lint>) {
+        let mut lint = |code, severity, field: &str, message: String| {
+            lints.push(Lint {
+                id: self.id.clone(),
+                code,
+                severity,
+                field: field.to_owned(),
+                message,
+            });
+        };
+
+        if let Some(message) = invalid_id(&self.id) {
+            lint("invalid-id", Severity::Error, "id", message);
+        }
+
+        if is_missing_description(&self.description) {
+            lint("missing-description", Severity::Warning, "description", "The group has no description".into());
+        }
+
+        for member in &self.packages {
+            match member {
+                Member::Definition(Definition::Package(package)) => package.lint(lints),
+                Member::Definition(Definition::Group(group)) => group.lint(lints),
+                Member::Reference(_) => {}
+            }
+        }
+    }
+}
+
+impl Package {
+    fn lint(&self, lints: &mut Vec<Lint>) {
+        let mut lint = |code, severity, field: &str, message: String| {
+            lints.push(Lint {
+                id: self.id.clone(),
+                code,
+                severity,
+                field: field.to_owned(),
+                message,
+            });
+        };
+
+        if let Some(message) = invalid_id(&self.id) {
+            lint("invalid-id", Severity::Error, "id", message);
+        }
+
+        if Version::parse(&self.version).is_none() {
+            lint(
+                "invalid-version",
+                Severity::Error,
+                "version",
+                format!("The version \"{}\" is not of the form `major.minor.patch`", self.version),
+            );
+        }
+
+        if is_missing_description(&self.description) {
+            lint("missing-description", Severity::Warning, "description", "The package has no description".into());
+        }
+
+        if self.authors.iter().all(|author| author.trim().is_empty()) {
+            lint("missing-authors", Severity::Warning, "authors", "The package has no authors".into());
+        }
+
+        if let Src::Path(path) = &self.src
+            && path.is_absolute()
+        {
+            lint(
+                "absolute-src",
+                Severity::Error,
+                "src",
+                format!("The source \"{}\" is an absolute path on the host, use a path relative to the package file", path.display()),
+            );
+        }
+
+        for (field, deps) in [("build_deps", &self.build_deps), ("runtime_deps", &self.runtime_deps)] {
+            for dep in deps.iter().filter(|dep| dep.version.is_none()) {
+                lint("unversioned-dependency", Severity::Warning, field, format!("The dependency \"{dep}\" doesn't specify a version"));
+            }
+        }
+
+        // This is only a heuristic, the install script is never run here.
+        for output in &self.expected_output {
+            let Some(file_name) = output.file_name().and_then(|name| name.to_str()) else {
+                continue;
+            };
+
+            if !self.install.contains(file_name) {
+                lint(
+                    "unwritten-output",
+                    Severity::Warning,
+                    "expected_output",
+                    format!("The install script never seems to write \"{}\"", output.display()),
+                );
+            }
+        }
+    }
+}
+
+fn is_missing_description(description: &str) -> bool {
+    description.trim().is_empty() || description == "No description"
+}
+
+/// Check an id against the naming policy: lowercase ASCII letters, digits and dashes, starting with a letter.
+fn invalid_id(id: &str) -> Option<String> {
+    let valid = id.starts_with(|c: char| c.is_ascii_lowercase()) && id.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-') && !id.ends_with('-') && !id.contains("--");
+
+    (!valid).then(|| format!("The id \"{id}\" must be lowercase letters, digits and single dashes, starting with a letter"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn lint(definition: serde_json::Value) -> Vec<(String, &'static str, Severity, String)> {
+        let definition = serde_json::from_value::<Definition>(definition).unwrap();
+
+        definition.lint().into_iter().map(|lint| (lint.id, lint.code, lint.severity, lint.field)).collect()
+    }
+
+    fn hello() -> serde_json::Value {
+        json!({
+            "id": "hello",
+            "version": "1.0.0",
+            "description": "Prints a greeting",
+            "authors": ["Jane Doe <jane@example.com>"],
+            "build_deps": ["gcc@13.2.0"],
+            "src": ".",
+            "expected_output": ["/bin/hello"],
+            "build": "gcc hello.c -o hello",
+            "install": "mkdir $env.out/bin; cp hello $env.out/bin/hello",
+        })
+    }
+
+    #[test]
+    fn well_formed_packages_have_no_lints() {
+        assert!(lint(json!({ "kind": "Package", "data": hello() })).is_empty());
+    }
+
+    #[test]
+    fn common_mistakes_are_reported() {
+        let package = json!({
+            "id": "Hello_World",
+            "version": "1.0",
+            "build_deps": ["gcc"],
+            "src": "/home/jane/hello",
+            "expected_output": ["/bin/hello"],
+            "build": "gcc hello.c -o hello",
+            "install": "cp a.out $env.out",
+        });
+
+        let lints = lint(json!({ "kind": "Package", "data": package }));
+        let found = |code: &str, severity, field: &str| lints.iter().any(|lint| lint.1 == code && lint.2 == severity && lint.3 == field);
+
+        assert!(found("invalid-id", Severity::Error, "id"));
+        assert!(found("invalid-version", Severity::Error, "version"));
+        assert!(found("missing-description", Severity::Warning, "description"));
+        assert!(found("missing-authors", Severity::Warning, "authors"));
+        assert!(found("absolute-src", Severity::Error, "src"));
+        assert!(found("unversioned-dependency", Severity::Warning, "build_deps"));
+        assert!(found("unwritten-output", Severity::Warning, "expected_output"));
+        assert_eq!(lints.len(), 7);
+    }
+
+    #[test]
+    fn groups_lint_their_members() {
+        let mut broken = hello();
+        broken["id"] = json!("broken-");
+
+        let group = json!({
+            "kind": "Group",
+            "data": {
+                "id": "tools",
+                "packages": [
+                    { "kind": "Package", "data": hello() },
+                    { "kind": "Group", "data": { "id": "nested", "description": "Nested tools", "packages": [{ "kind": "Package", "data": broken }] } },
+                    "Not_An_Id",
+                ],
+            },
+        });
+
+        assert_eq!(
+            lint(group),
+            [
+                ("tools".to_owned(), "missing-description", Severity::Warning, "description".to_owned()),
+                ("broken-".to_owned(), "invalid-id", Severity::Error, "id".to_owned()),
+            ]
+        );
+    }
+
+    #[test]
+    fn ids_follow_the_naming_policy() {
+        for id in ["hello", "hello-world", "lib2", "a"] {
+            assert!(invalid_id(id).is_none(), "{id}");
+        }
+
+        for id in ["", "Hello", "2fa", "hello_world", "hello-", "hello--world", "-hello"] {
+            assert!(invalid_id(id).is_some(), "{id}");
+        }
+    }
+}
@@ -27,14 +27,14 @@ impl super::PackageManager {
             }
         }
 
-        File::create(self.store().join("garbage")).context("init_root: create garbage tracker")?;
-
         // Create base generation
+        self.create_generation_dir(1)?;
         fs::create_dir_all(self.generations().join("1/bin")).context("init_root: create base generation bin directory")?;
         fs::create_dir_all(self.generations().join("1/lib")).context("init_root: create base generation lib directory")?;
         fs::create_dir_all(self.generations().join("1/config")).context("init_root: create base generation config directory")?;
 
         self.set_current_generation(1)?;
+        self.set_boot_generation(1)?;
 
         self.with_root_cwd(|| {
             symlink(self.generations_raw().join("current/bin"), "bin").context("init_root: symlink current generation 'bin' to '/bin'")?;
@@ -49,8 +49,16 @@ impl super::PackageManager {
         fs::write(self.config().join("system/env.tl"), include_str!("./base-config/env.tl")).context("init_root: copy base environment config")?;
         fs::write(self.config().join("system/services.tl"), include_str!("./base-config/services.tl")).context("init_root: copy base services config")?;
         fs::write(self.config().join("system/users.tl"), include_str!("./base-config/users.tl")).context("init_root: copy base users config")?;
+        fs::write(self.config().join("system/gc.tl"), include_str!("./base-config/gc.tl")).context("init_root: copy base gc config")?;
+        fs::write(self.config().join("system/store.tl"), include_str!("./base-config/store.tl")).context("init_root: copy base store config")?;
+        fs::write(self.config().join("system/sandbox.tl"), include_str!("./base-config/sandbox.tl")).context("init_root: copy base sandbox config")?;
+        fs::write(self.config().join("system/caches.tl"), include_str!("./base-config/caches.tl")).context("init_root: copy base binary caches config")?;
+        fs::create_dir(self.config().join("system/overlays")).context("init_root: create package overlays directory")?;
+        File::create(self.store().join("lock")).context("init_root: create the store's lock file")?;
 
-        self.store_set_immutable(true)?;
+        if self.store_config()?.immutable {
+            self.store_set_immutable(true)?;
+        }
 
         Ok(())
     }
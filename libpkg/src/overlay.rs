@@ -0,0 +1,129 @@
+use crate::{
+    error::{Context, PackageManagerError},
+    package::{deserialize_error, Package},
+    stdlib,
+    trace::Tracer,
+};
+use prelude::logger::{make_fatal, Log};
+use serde::Deserialize;
+use std::{fs, mem, path::PathBuf};
+use tl::{
+    parser::parse,
+    runtime::{
+        types::{NativeFunction, Value},
+        Scope,
+    },
+    Source,
+};
+
+impl crate::PackageManager {
+    /// Apply the overlays for a package from `config/system/overlays`, recording their names in [`Package::overlays`].
+    /// The overlays of a package are `<id>.tl` and every `<id>.<name>.tl`, applied in order of their file names.
+    /// Each gets the package as it was before it from `original()` and evaluates to the whole modified package, whose id can't change.
+    pub fn apply_overlays(&self, package: &mut Package) -> Result<(), PackageManagerError> {
+        let dir = self.config().join("system/overlays");
+        package.overlays.clear();
+
+        if !dir.exists() {
+            return Ok(());
+        }
+
+        let mut overlays = fs::read_dir(&dir)
+            .context("apply_overlays: list the overlays")?
+            .filter_map(Result::ok)
+            .map(|entry| entry.file_name().to_string_lossy().into_owned())
+            .filter(|name| name.strip_suffix(".tl").is_some_and(|stem| stem == package.id || stem.starts_with(&format!("{}.", package.id))))
+            .collect::<Vec<_>>();
+        overlays.sort();
+
+        for name in overlays {
+            let mut overlaid = eval_overlay(dir.join(&name), package)?;
+
+            if overlaid.id != package.id {
+                let message = format!("The overlay '{name}' changed the id of \"{}\" to \"{}\", overlays can't change ids", package.id, overlaid.id);
+                return Err(PackageManagerError::ConfigEval(Box::new(make_fatal!("{message}"))));
+            }
+
+            overlaid.path = package.path.take();
+            overlaid.overlays = mem::take(&mut package.overlays);
+            overlaid.overlays.push(name);
+            *package = overlaid;
+        }
+
+        Ok(())
+    }
+}
+
+/// Evaluate an overlay against the package as it is so far.
+fn eval_overlay(path: PathBuf, package: &Package) -> Result<Package, PackageManagerError> {
+    let source = Source::from_path(&path).context(format!("eval_overlay: read overlay '{}'", path.display()))?;
+    let ast = parse(&source).map_err(|err| PackageManagerError::ConfigEval(Box::new(Log::from(*err))))?;
+    let mut scope = Scope::new(source, ast);
+
+    stdlib::register(&mut scope, &Tracer::default(), path.canonicalize().ok());
+
+    let json = serde_json::to_value(package).context("eval_overlay: serialize package")?;
+    let original = Value::deserialize(json).context("eval_overlay: convert package")?;
+
+    // `original()` is the whole package before the overlay.
+    scope.add_native_fn(
+        "original",
+        NativeFunction::Strict {
+            params: 0,
+            func: Box::new(move |_| Ok(original.clone())),
+        },
+    );
+
+    let value = scope.eval().map_err(|err| PackageManagerError::ConfigEval(Box::new(Log::from(*err))))?;
+
+    serde_path_to_error::deserialize(value).map_err(|err| PackageManagerError::ConfigEval(Box::new(make_fatal!("{}", deserialize_error(&err)))))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::test::{package, root};
+
+    fn write_overlay(pm: &crate::PackageManager, name: &str, contents: &str) {
+        let dir = pm.config().join("system/overlays");
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join(name), contents).unwrap();
+    }
+
+    #[test]
+    fn overlays_are_applied_in_order_to_the_whole_package() {
+        let (_root, pm) = root(None);
+        write_overlay(
+            &pm,
+            "foo.tl",
+            "let pkg = original()\n\n{ id = pkg.id version = \"2.0.0\" runtime_deps = pkg.runtime_deps src = pkg.src build = \"make\" install = pkg.install }\n",
+        );
+        write_overlay(
+            &pm,
+            "foo.verbose.tl",
+            "let pkg = original()\n\n{ id = pkg.id version = \"${pkg.version}-1\" runtime_deps = pkg.runtime_deps src = pkg.src build = \"${pkg.build} V=1\" install = pkg.install }\n",
+        );
+        write_overlay(&pm, "foobar.tl", "{ id = \"foobar\" src = \".\" build = \"\" install = \"\" }\n");
+
+        let mut foo = package("foo", "1.0.0", &["bar"]);
+        pm.apply_overlays(&mut foo).unwrap();
+
+        assert_eq!(foo.version, "2.0.0-1");
+        assert_eq!(foo.build, "make V=1");
+        assert_eq!(foo.runtime_deps.iter().map(ToString::to_string).collect::<Vec<_>>(), ["bar"]);
+        assert_eq!(foo.overlays, ["foo.tl", "foo.verbose.tl"]);
+    }
+
+    #[test]
+    fn overlays_cant_change_the_id_or_return_invalid_packages() {
+        let (_root, pm) = root(None);
+
+        write_overlay(&pm, "foo.tl", "{ id = \"bar\" src = \".\" build = \"\" install = \"\" }\n");
+        let result = pm.apply_overlays(&mut package("foo", "1.0.0", &[]));
+        assert!(matches!(result, Err(PackageManagerError::ConfigEval(_))));
+
+        write_overlay(&pm, "foo.tl", "{ id = \"foo\" version = 2 src = \".\" build = \"\" install = \"\" }\n");
+        let result = pm.apply_overlays(&mut package("foo", "1.0.0", &[]));
+        assert!(matches!(result, Err(PackageManagerError::ConfigEval(_))));
+    }
+}
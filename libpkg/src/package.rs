@@ -1,14 +1,24 @@
-use prelude::logger::{make_error, make_fatal, Log};
-use serde::{Deserialize, Serialize};
+use crate::{
+    diagnostic::{diagnostic, render, SourceText},
+    sandbox::Limits,
+    stdlib,
+    trace::{TraceEvent, Tracer},
+};
+use prelude::logger::{make_error, Log};
+use serde::{
+    de::{Error as _, IgnoredAny},
+    Deserialize, Serialize,
+};
 use serde_inline_default::serde_inline_default;
-use std::path::PathBuf;
+use serde_path_to_error::Segment;
+use std::{
+    collections::BTreeMap,
+    fmt::{self, Display},
+    path::{Path, PathBuf},
+};
 use tl::{
-    object,
     parser::parse,
-    runtime::{
-        types::{NativeFunction, Value},
-        Scope,
-    },
+    runtime::{types::Value, Scope},
     Source,
 };
 
@@ -45,49 +55,356 @@ pub struct Package {
     #[serde(default)]
     pub expected_output: Vec<PathBuf>,
 
+    /// The stage that fetches everything the build needs from the network.
+    #[serde(default)]
+    pub fetch: Option<Fetch>,
     /// The nushell script that will be ran for the build stage.
+    /// Every stage gets `$env.PKG_ID`, `$env.PKG_NAME`, `$env.PKG_VERSION`, `$env.out`, `$env.src`, `$env.build`
+    /// and a `$env.PKG_DEP_<ID>` per dependency, each also declared as a plain variable.
     pub build: String,
     /// The nushell script that will be ran for the install stage.
+    /// Only what it writes to `$env.out` ends up in the store.
     pub install: String,
 
+    /// Resource limits for the build, unset limits fall back to the ones in the sandbox config.
+    #[serde(default)]
+    pub limits: Limits,
+
+    /// File names of the overlays applied to the package, set by the package manager.
+    #[serde(default)]
+    pub overlays: Vec<String>,
+
     /// Path to the package file.
     #[serde(skip)]
     pub(crate) path: Option<PathBuf>,
 }
 
+/// What a package file evaluates to, tagged by the function that created it.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "kind", content = "data")]
+pub enum Definition {
+    /// A single package, created with `package({ ... })`.
+    Package(Package),
+    /// A collection of packages, created with `group({ ... })`.
+    Group(Group),
+}
+
+/// A named collection of packages that are installed together.
+#[serde_inline_default]
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Group {
+    /// String used to identify the group.
+    pub id: String,
+    /// Name of the group that will be displayed to user.
+    /// Defaults to id.
+    #[serde(default)]
+    pub name: String,
+    /// Description of the group.
+    #[serde_inline_default("No description".into())]
+    pub description: String,
+    /// Packages and nested groups defined inline, or references to packages by id.
+    #[serde(default)]
+    pub packages: Vec<Member>,
+}
+
+/// A member of a group.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(untagged, expecting = "a `package({ ... })`, a `group({ ... })` or a package id such as \"foo@1.0\"")]
+pub enum Member {
+    /// A package or nested group defined inline with `package({ ... })` or `group({ ... })`.
+    Definition(Definition),
+    /// A reference to a package by id, written like a dependency.
+    Reference(Dependency),
+}
+
+impl Group {
+    /// Flatten the group into the packages it defines and the packages it references, including those of nested groups.
+    pub fn members(self) -> (Vec<Package>, Vec<Dependency>) {
+        let (mut packages, mut references) = (Vec::new(), Vec::new());
+
+        for member in self.packages {
+            match member {
+                Member::Definition(Definition::Package(package)) => packages.push(package),
+                Member::Definition(Definition::Group(group)) => {
+                    let (nested_packages, nested_references) = group.members();
+                    packages.extend(nested_packages);
+                    references.extend(nested_references);
+                }
+                Member::Reference(dependency) => references.push(dependency),
+            }
+        }
+
+        (packages, references)
+    }
+}
+
+/// The `kind` tag that the `package` and `group` functions wrap their input with, the `data` is ignored.
+#[derive(Deserialize)]
+struct Tag {
+    #[serde(default)]
+    kind: Option<String>,
+}
+
+/// Fields of a package that have no default.
+const PACKAGE_REQUIRED: &[&str] = &["id", "src", "build", "install"];
+/// Fields of a group that have no default.
+const GROUP_REQUIRED: &[&str] = &["id"];
+
+/// An invalid field of the object passed to the `package` or `group` function.
+pub(crate) struct FieldError {
+    /// The field that is set to an invalid value, unset for missing fields and errors about the whole object.
+    pub field: Option<String>,
+    pub message: String,
+    pub help: Option<&'static str>,
+}
+
+/// Check the object passed to the `package` or `group` function, so that invalid definitions are reported at the call that made them.
+pub(crate) fn check_definition(kind: &str, data: &Value) -> Result<(), FieldError> {
+    let required = if kind == "Package" { PACKAGE_REQUIRED } else { GROUP_REQUIRED };
+    let keys = BTreeMap::<String, IgnoredAny>::deserialize(data.clone()).map_err(|err| FieldError {
+        field: None,
+        message: format!("Invalid {}: {err}", kind.to_lowercase()),
+        help: None,
+    })?;
+
+    if let Some(missing) = required.iter().find(|field| !keys.contains_key(**field)) {
+        return Err(FieldError {
+            field: None,
+            message: format!("Missing field `{missing}`"),
+            help: suggestion(missing),
+        });
+    }
+
+    let result = match kind {
+        "Package" => serde_path_to_error::deserialize::<_, Package>(data.clone()).map(|_| ()),
+        _ => serde_path_to_error::deserialize::<_, Group>(data.clone()).map(|_| ()),
+    };
+
+    result.map_err(|err| describe(&err))
+}
+
+/// The id set in the object passed to the `package` or `group` function, if it is a string.
+pub(crate) fn definition_id(data: &Value) -> Option<String> {
+    #[derive(Deserialize)]
+    struct Id {
+        id: String,
+    }
+
+    Id::deserialize(data.clone()).ok().map(|Id { id }| id)
+}
+
+/// Describe a deserialization error by the field it is about, with a hint on how to write that field.
+pub(crate) fn deserialize_error<E: Display>(err: &serde_path_to_error::Error<E>) -> String {
+    let FieldError { message, help, .. } = describe(err);
+
+    render(None, None, &message, help)
+}
+
+/// Name the field a deserialization error is about in its message, with a hint on how to write that field.
+fn describe<E: Display>(err: &serde_path_to_error::Error<E>) -> FieldError {
+    // The `data` the `package` and `group` functions wrap their input in isn't part of what the user wrote.
+    let mut field = None;
+    let mut path = String::new();
+
+    for segment in err.path().iter() {
+        match segment {
+            Segment::Map { key } if key == "data" => {}
+            Segment::Map { key } => {
+                if !path.is_empty() {
+                    path.push('.');
+                }
+
+                path.push_str(key);
+                field = Some(key.as_str());
+            }
+            Segment::Seq { index } => path.push_str(&format!("[{index}]")),
+            Segment::Enum { .. } | Segment::Unknown => {}
+        }
+    }
+
+    let message = match path.as_str() {
+        "" => format!("Invalid value: {}", err.inner()),
+        path => format!("Invalid value for `{path}`: {}", err.inner()),
+    };
+
+    FieldError {
+        field: field.map(ToOwned::to_owned),
+        message,
+        help: field.and_then(suggestion),
+    }
+}
+
+/// A hint on how to write a field of a package or a group.
+fn suggestion(field: &str) -> Option<&'static str> {
+    Some(match field {
+        "id" => "`id` is a string that identifies the package, such as \"hello\"",
+        "name" | "description" | "version" => "this field is a string",
+        "authors" => "`authors` is a list of strings, such as [ \"Jane Doe <jane@example.com>\" ]",
+        "build_deps" | "runtime_deps" => "dependencies are lists of strings, written as \"id\" or \"id@version\"",
+        "src" => "`src` is a path relative to the package file such as \".\", `fetchGit({ ... })` or `fetchUrl({ ... })`",
+        "expected_output" => "`expected_output` is a list of paths relative to `$out`, such as [ \"/bin/hello\" ]",
+        "build" => "`build` is the nushell script that builds the package, such as \"cargo build --release\"",
+        "install" => "`install` is the nushell script that copies the build's results to `$env.out`",
+        "fetch" => "`fetch` is an object with a nushell `script` and the `hash` of what it fetches",
+        "limits" => "`limits` is an object with `timeout`, `cpu`, `memory`, `processes` and `output_size`",
+        "packages" => "a group's `packages` is a list of `package({ ... })`, `group({ ... })` or package ids",
+        _ => return None,
+    })
+}
+
+impl Definition {
+    /// Deserialize an evaluated value, dispatching on its `kind` tag.
+    /// The `package` and `group` functions already checked their input, so only values that weren't created by them are rejected here,
+    /// pointing at the expression the file evaluates to.
+    fn from_value(value: Value, text: Option<&SourceText>) -> Result<Self, Box<Log>> {
+        let error = |message: &str, help: Option<&str>| diagnostic(text, text.and_then(SourceText::result), message, help);
+
+        match Tag::deserialize(value.clone()).ok().and_then(|tag| tag.kind).as_deref() {
+            Some("Package" | "Group") => serde_path_to_error::deserialize(value).map_err(|err| {
+                let FieldError { message, help, .. } = describe(&err);
+                error(&message, help)
+            }),
+            Some(kind) => Err(error(
+                &format!("Unknown kind \"{kind}\", expected \"Package\" or \"Group\""),
+                Some("create packages with `package({ ... })` and groups with `group({ ... })` instead of setting `kind` by hand"),
+            )),
+            None => Err(error(
+                "The package file must evaluate to a package or a group",
+                Some("wrap the object in `package({ ... })` or `group({ ... })`"),
+            )),
+        }
+    }
+
+    /// Fill in the defaults that depend on other fields, and the path of the file every package came from.
+    fn finish(&mut self, path: &Option<PathBuf>) {
+        match self {
+            Self::Package(package) => {
+                // Set the `name` property to the `id` if it's not set.
+                if package.name.is_empty() {
+                    package.name = package.id.clone();
+                }
+
+                package.path = path.clone();
+            }
+            Self::Group(group) => {
+                if group.name.is_empty() {
+                    group.name = group.id.clone();
+                }
+
+                for member in &mut group.packages {
+                    if let Member::Definition(definition) = member {
+                        definition.finish(path);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// A fetch stage, the only stage that runs with network access.
+/// Its output is exposed read-only to the build at `/fetched`, but only once it matches the expected hash.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Fetch {
+    /// The nushell script that will be ran for the fetch stage, it must write everything it fetches to `/out`.
+    /// It runs in its own copy of the source, so only what it writes to `/out` reaches the build.
+    pub script: String,
+    /// The expected hash of the fetch stage's output, in the `sha256:<hex>` format.
+    pub hash: String,
+}
+
 #[derive(Debug)]
 pub enum Src {
+    /// A path relative to the package file, any string that isn't a `fetchGit` or `fetchUrl` object.
     Path(PathBuf),
+    /// A git repository, given with `fetchGit({ url, rev })`.
     // TODO: Use a proper URL type
-    Git(String),
+    Git { url: String, rev: Option<String> },
+    /// A file or archive downloaded with `fetchUrl({ url, hash })`.
+    Url { url: String, hash: Option<String> },
+}
+
+/// The objects that `fetchGit` and `fetchUrl` create, which is also how fetched sources are written to store metadata.
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "kind", content = "data")]
+enum FetchedSrc {
+    Git {
+        url: String,
+        #[serde(default)]
+        rev: Option<String>,
+    },
+    Url {
+        url: String,
+        #[serde(default)]
+        hash: Option<String>,
+    },
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(untagged, expecting = "a path, `fetchGit({ ... })` or `fetchUrl({ ... })`")]
+enum RawSrc {
+    Path(PathBuf),
+    Fetched(FetchedSrc),
+}
+
+/// The form of a source in binary formats such as archive headers, which can't tell a string from an object.
+#[derive(Serialize, Deserialize)]
+enum BinarySrc {
+    Path(PathBuf),
+    Git { url: String, rev: Option<String> },
+    Url { url: String, hash: Option<String> },
+}
+
+/// Sources are displayed as a path, `git+<url>#<rev>` or `url+<url>#<hash>`, where the fragment is optional.
+impl Display for Src {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (prefix, url, fragment) = match self {
+            Self::Path(path) => return write!(f, "{}", path.display()),
+            Self::Git { url, rev } => ("git", url, rev),
+            Self::Url { url, hash } => ("url", url, hash),
+        };
+
+        match fragment {
+            Some(fragment) => write!(f, "{prefix}+{url}#{fragment}"),
+            None => write!(f, "{prefix}+{url}"),
+        }
+    }
 }
 
 impl Serialize for Src {
-    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
-    where
-        S: serde::Serializer,
-    {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        if !serializer.is_human_readable() {
+            return match self {
+                Self::Path(path) => BinarySrc::Path(path.clone()),
+                Self::Git { url, rev } => BinarySrc::Git { url: url.clone(), rev: rev.clone() },
+                Self::Url { url, hash } => BinarySrc::Url { url: url.clone(), hash: hash.clone() },
+            }
+            .serialize(serializer);
+        }
+
         match self {
-            Src::Path(path) => serializer.serialize_str(&path.display().to_string()),
-            Src::Git(url) => serializer.serialize_str(url),
+            Self::Path(path) => RawSrc::Path(path.clone()),
+            Self::Git { url, rev } => RawSrc::Fetched(FetchedSrc::Git { url: url.clone(), rev: rev.clone() }),
+            Self::Url { url, hash } => RawSrc::Fetched(FetchedSrc::Url { url: url.clone(), hash: hash.clone() }),
         }
+        .serialize(serializer)
     }
 }
 
 impl<'de> Deserialize<'de> for Src {
-    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
-    where
-        D: serde::Deserializer<'de>,
-    {
-        let string = String::deserialize(deserializer)?;
-        let path = PathBuf::from(&string);
-
-        // TODO: Once more source types are implemented this will need to be a bit smarter
-        if path.exists() {
-            Ok(Self::Path(path))
-        } else {
-            Ok(Self::Git(string))
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        if !deserializer.is_human_readable() {
+            return Ok(match BinarySrc::deserialize(deserializer)? {
+                BinarySrc::Path(path) => Self::Path(path),
+                BinarySrc::Git { url, rev } => Self::Git { url, rev },
+                BinarySrc::Url { url, hash } => Self::Url { url, hash },
+            });
         }
+
+        Ok(match RawSrc::deserialize(deserializer)? {
+            RawSrc::Path(path) => Self::Path(path),
+            RawSrc::Fetched(FetchedSrc::Git { url, rev }) => Self::Git { url, rev },
+            RawSrc::Fetched(FetchedSrc::Url { url, hash }) => Self::Url { url, hash },
+        })
     }
 }
 
@@ -97,89 +414,144 @@ pub struct Dependency {
     pub version: Option<String>,
 }
 
-impl Serialize for Dependency {
-    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+impl Display for Dependency {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match &self.version {
-            Some(version) if !version.is_empty() => serializer.serialize_str(&format!("{}@{}", self.id, version)),
-            _ => serializer.serialize_str(&self.id),
+            Some(version) if !version.is_empty() => write!(f, "{}@{}", self.id, version),
+            _ => write!(f, "{}", self.id),
         }
     }
 }
 
+impl Serialize for Dependency {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
 impl<'de> Deserialize<'de> for Dependency {
     fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
         let s = String::deserialize(deserializer)?;
         let parts: Vec<&str> = s.split('@').collect();
 
+        if parts.len() > 2 || parts.iter().any(|part| part.is_empty() || part.contains(char::is_whitespace)) {
+            return Err(D::Error::custom(format!("invalid dependency \"{s}\", expected \"id\" or \"id@version\"")));
+        }
+
         Ok(Self {
             id: parts[0].to_string(),
-            version: parts.iter().skip(1).last().map(ToString::to_string),
+            version: parts.get(1).map(ToString::to_string),
         })
     }
 }
 
 impl Package {
-    pub fn eval(source: impl Into<Source>) -> Result<Self, Box<Log>> {
+    /// Evaluate a package file, which defines either a single package or a group of packages.
+    pub fn eval(source: impl Into<Source>) -> Result<Definition, Box<Log>> {
+        Self::eval_with(source, Tracer::default())
+    }
+
+    /// Evaluate a package file like [`eval`](Self::eval), also returning every native function call and the value it evaluated to.
+    /// The trace is returned even if evaluation failed, up to the point where it failed.
+    pub fn eval_traced(source: impl Into<Source>) -> (Result<Definition, Box<Log>>, Vec<TraceEvent>) {
+        let tracer = Tracer::new(true);
+        let result = Self::eval_with(source, tracer.clone());
+
+        (result, tracer.finish())
+    }
+
+    fn eval_with(source: impl Into<Source>, tracer: Tracer) -> Result<Definition, Box<Log>> {
         let source = source.into();
         let package_path = source.path.clone().map(|p| p.canonicalize().unwrap_or(p));
+        let text = SourceText::read(&source);
         let ast = parse(&source).map_err(|err| Log::from(*err))?;
         let mut scope = Scope::new(source, ast);
 
-        scope.add_native_fn(
-            "package",
-            NativeFunction::Strict {
-                params: 1,
-                func: Box::new(|args| {
-                    let Some(data @ Value::Object(_)) = args.first() else {
-                        return Err(Box::new(tl::Error::new(
-                            tl::runtime::ErrorType::NativeFnError("The `package` function requires an object as input".into()),
-                            None,
-                        )));
-                    };
-
-                    Ok(object!(kind = Value::String("Package".into()), data = data.clone()))
-                }),
-            },
-        );
-
-        scope.add_native_fn(
-            "group",
-            NativeFunction::Strict {
-                params: 1,
-                func: Box::new(|args| {
-                    let Some(data @ Value::Object(_)) = args.first() else {
-                        return Err(Box::new(tl::Error::new(
-                            tl::runtime::ErrorType::NativeFnError("The `group` function requires an object as input".into()),
-                            None,
-                        )));
-                    };
-
-                    Ok(object!(kind = Value::String("Group".into()), data = data.clone()))
-                }),
-            },
-        );
+        stdlib::register(&mut scope, &tracer, package_path.clone());
 
-        let evaluated: Option<Package> = match scope.eval() {
+        let evaluated: Option<Definition> = match scope.eval() {
             Ok(value) if value != Value::Null => {
-                dbg!(&value);
-                Ok(Some(Deserialize::deserialize(value).map_err(|err| Box::new(make_fatal!("Could not deserialize value: {err}")))?))
+                tracer.result(&value);
+                Ok(Some(Definition::from_value(value, text.as_ref())?))
             }
             Ok(_) => Ok(None),
             Err(err) => Err(Box::new(Log::from(*err))),
         }?;
 
         match evaluated {
-            Some(mut package) => {
-                // Set the `name` property to the `id` if it's not set.
-                if package.name.is_empty() {
-                    package.name = package.id.clone();
-                }
-
-                package.path = package_path;
+            Some(mut definition) => {
+                definition.finish(&package_path);
 
-                Ok(package)
+                Ok(definition)
             }
             None => Err(Box::new(make_error!("Package source did not evaluate to anything."))),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::test::TempDir;
+    use std::fs;
+
+    /// Evaluate a package file, giving the message of the error it fails with.
+    fn error(text: &str) -> String {
+        let dir = TempDir::new();
+        let path = dir.join("package.tl");
+        fs::write(&path, text).unwrap();
+
+        match Package::eval(Source::from_path(&path).unwrap()) {
+            Ok(_) => panic!("expected the package file to fail"),
+            Err(err) => err.to_string(),
+        }
+    }
+
+    #[test]
+    fn unknown_kinds_point_at_the_result() {
+        let err = error("let data = { id = \"foo\" }\n\n{\n    kind = \"Pakage\"\n    data = data\n}\n");
+
+        assert!(err.contains("Unknown kind \"Pakage\""), "{err}");
+        assert!(err.contains("package.tl:3:1"), "{err}");
+    }
+
+    #[test]
+    fn bare_objects_point_at_the_result() {
+        let err = error("let src = \".\"\n\n{ id = \"foo\" src = src build = \"\" install = \"\" }\n");
+
+        assert!(err.contains("must evaluate to a package or a group"), "{err}");
+        assert!(err.contains("package.tl:3:1"), "{err}");
+        assert!(err.contains("wrap the object in `package({ ... })`"), "{err}");
+    }
+
+    #[test]
+    fn strings_are_always_paths() {
+        for string in ["./src", "https://example.com/foo.git", "git+https://example.com/foo.git#main"] {
+            let src = serde_json::from_value::<Src>(serde_json::json!(string)).unwrap();
+
+            assert!(matches!(&src, Src::Path(path) if path == Path::new(string)), "{string}: {src:?}");
+        }
+    }
+
+    #[test]
+    fn sources_round_trip_through_store_metadata_and_archives() {
+        let sources = [
+            Src::Path("src".into()),
+            Src::Git {
+                url: "https://example.com/foo.git".into(),
+                rev: Some("main".into()),
+            },
+            Src::Url {
+                url: "https://example.com/foo.tar.gz".into(),
+                hash: None,
+            },
+        ];
+
+        for src in sources {
+            let json = serde_json::from_slice::<Src>(&serde_json::to_vec(&src).unwrap()).unwrap();
+            let binary = bincode::deserialize::<Src>(&bincode::serialize(&src).unwrap()).unwrap();
+
+            assert_eq!((json.to_string(), binary.to_string()), (src.to_string(), src.to_string()));
+        }
+    }
+}
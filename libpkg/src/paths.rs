@@ -30,4 +30,12 @@ paths!(
     store store_raw "store",
     /// Return the path to the configs relative to the root.
     config config_raw "config",
+    /// Return the path to the store's transaction journal relative to the root.
+    journal journal_raw "system/journal",
+    /// Return the path to the scratch directories of builds relative to the root.
+    scratch scratch_raw "system/scratch",
+    /// Return the path to the build logs relative to the root.
+    logs logs_raw "system/logs",
+    /// Return the path to the lock files of the build users relative to the root.
+    build_users build_users_raw "system/build-users",
 );
@@ -0,0 +1,911 @@
+use crate::{
+    config::{duration, eval_config, size},
+    error::{Context, PackageManagerError},
+    util::disk_usage,
+};
+use nix::{
+    mount::{mount, umount2, MntFlags, MsFlags},
+    sched::{unshare, CloneFlags},
+    sys::{
+        resource::{setrlimit, Resource},
+        signal::{kill, killpg, raise, Signal},
+        wait::{waitpid, WaitPidFlag, WaitStatus},
+    },
+    unistd::{chdir, dup2, fexecve, fork, getegid, geteuid, pivot_root, setgid, setgroups, sethostname, setpgid, setuid, ForkResult, Gid, Pid, Uid, User},
+};
+use nu_embed::Engine;
+use prelude::logger::error;
+use rustix::fs::{fcntl_getfl, fcntl_setfl, flock, FlockOperation, OFlags};
+use serde::{Deserialize, Serialize};
+use serde_inline_default::serde_inline_default;
+use std::{
+    env,
+    ffi::CString,
+    fs::{self, File, OpenOptions},
+    io::{self, PipeReader, Read},
+    os::{
+        fd::AsRawFd,
+        unix::{ffi::OsStringExt, fs::lchown},
+    },
+    path::{Path, PathBuf},
+    process,
+    sync::atomic::{AtomicBool, Ordering},
+    thread,
+    time::{Duration, Instant},
+};
+
+/// Device nodes bound from the host into the sandbox's `/dev`.
+const DEVICES: &[&str] = &["null", "zero", "full", "random", "urandom"];
+/// Files from the host that a sandbox with network access needs to resolve names and verify TLS certificates, bound read-only where they exist.
+const NETWORK_FILES: &[&str] = &["/etc/resolv.conf", "/etc/hosts", "/etc/nsswitch.conf", "/etc/ssl/certs", "/etc/pki/tls/certs", "/etc/ca-certificates"];
+/// The cgroup v2 subtree that every build gets its own cgroup in.
+const CGROUP_ROOT: &str = "/sys/fs/cgroup/pkg-builds";
+/// The interval for checking if a build with a timeout has exited.
+const WAIT_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Sandbox settings, read from `config/system/sandbox.tl`.
+#[derive(Debug, Default, Clone, Deserialize)]
+pub struct SandboxConfig {
+    /// Run builds in a new user namespace, mapping the calling user to root inside the sandbox.
+    /// This lets builds run without full root where user namespaces are available.
+    #[serde(default)]
+    pub user_namespace: bool,
+    /// Pool of dedicated users that builds run as, ignored when a user namespace is used.
+    #[serde(default)]
+    pub build_users: BuildUsers,
+    /// Limits applied to every build, packages can override each of them.
+    #[serde(default)]
+    pub limits: Limits,
+    /// Nushell executable for debug shells, found on the `PATH` by default.
+    /// Only the executable itself is brought into the sandbox, so it has to be statically linked.
+    #[serde(default)]
+    pub shell: Option<PathBuf>,
+}
+
+impl SandboxConfig {
+    /// Find the nushell executable for debug shells.
+    pub(crate) fn nu_executable(&self) -> Result<PathBuf, PackageManagerError> {
+        match &self.shell {
+            Some(shell) if shell.is_file() => Ok(shell.clone()),
+            Some(_) => Err(PackageManagerError::NushellNotFound),
+            None => env::var_os("PATH")
+                .iter()
+                .flat_map(env::split_paths)
+                .map(|dir| dir.join("nu"))
+                .find(|path| path.is_file())
+                .ok_or(PackageManagerError::NushellNotFound),
+        }
+    }
+}
+
+/// Resource limits for a build, unset limits are unbounded.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct Limits {
+    /// Wall-clock time after which the build is killed.
+    #[serde(default, with = "duration")]
+    pub timeout: Option<Duration>,
+    /// CPU time the build may use.
+    #[serde(default, with = "duration")]
+    pub cpu: Option<Duration>,
+    /// Memory the build may use, in bytes.
+    #[serde(default, with = "size")]
+    pub memory: Option<u64>,
+    /// Number of processes the build may run at once.
+    #[serde(default)]
+    pub processes: Option<u64>,
+    /// Total size of what the build may write to its output directory, in bytes.
+    #[serde(default, with = "size")]
+    pub output_size: Option<u64>,
+}
+
+impl Limits {
+    /// Fill the limits that aren't set from the given fallback.
+    pub fn or(&self, fallback: &Limits) -> Limits {
+        Limits {
+            timeout: self.timeout.or(fallback.timeout),
+            cpu: self.cpu.or(fallback.cpu),
+            memory: self.memory.or(fallback.memory),
+            processes: self.processes.or(fallback.processes),
+            output_size: self.output_size.or(fallback.output_size),
+        }
+    }
+}
+
+/// A pool of build users named `<prefix>1` to `<prefix><count>`, each running at most one build at a time.
+#[serde_inline_default]
+#[derive(Debug, Clone, Deserialize)]
+pub struct BuildUsers {
+    #[serde_inline_default("pkgbuild".into())]
+    pub prefix: String,
+    #[serde_inline_default(4)]
+    pub count: u32,
+}
+
+impl Default for BuildUsers {
+    fn default() -> Self {
+        Self { prefix: "pkgbuild".into(), count: 4 }
+    }
+}
+
+/// A user that a build runs as.
+#[derive(Debug, Clone)]
+pub(crate) struct BuildUser {
+    pub name: String,
+    pub uid: Uid,
+    pub gid: Gid,
+}
+
+/// A build user allocated from the pool, released when dropped.
+#[derive(Debug)]
+pub(crate) struct BuildUserLease {
+    pub user: BuildUser,
+    _lock: File,
+}
+
+/// An isolated environment for running a build.
+/// The sandbox gets its own mount, PID, UTS, IPC and network namespaces with a tmpfs `/`,
+/// the output directory at `/out`, a scratch working directory at `/build`, the source read-only at `/src`
+/// and every dependency bound read-only at `/store/<name>`.
+/// The output of a fetch stage is bound read-only at `/fetched`.
+#[derive(Debug)]
+pub(crate) struct Sandbox {
+    /// Directory on the host that the sandbox's tmpfs root is mounted on.
+    pub root: PathBuf,
+    /// Directory on the host bound read-write to `/out`.
+    pub out: PathBuf,
+    /// Directory on the host bound read-only to `/src`.
+    pub src: PathBuf,
+    /// Directory on the host bound read-write to `/build`, the working directory of the sandbox.
+    pub build: PathBuf,
+    /// Store items bound read-only to `/store/<name>`.
+    pub deps: Vec<PathBuf>,
+    /// Directory on the host bound read-only to `/fetched`.
+    pub fetched: Option<PathBuf>,
+    /// Environment variables set in the sandbox, also declared as nushell variables in front of every script.
+    pub env: Vec<(String, String)>,
+    /// Keep the host's network namespace.
+    pub network: bool,
+    /// User to run the build as, unused when a user namespace is used.
+    pub user: Option<BuildUser>,
+    pub limits: Limits,
+    pub config: SandboxConfig,
+}
+
+impl crate::PackageManager {
+    /// Read the sandbox settings, the default settings don't use a user namespace.
+    pub fn sandbox_config(&self) -> Result<SandboxConfig, PackageManagerError> {
+        let path = self.config().join("system/sandbox.tl");
+
+        if !path.exists() {
+            return Ok(SandboxConfig::default());
+        }
+
+        eval_config(path)
+    }
+
+    /// Allocate a free user from the build user pool, waiting for the first user if all of them are busy.
+    pub(crate) fn allocate_build_user(&self, config: &SandboxConfig) -> Result<BuildUserLease, PackageManagerError> {
+        let BuildUsers { prefix, count } = &config.build_users;
+
+        fs::create_dir_all(self.build_users()).context("allocate_build_user: create the build users' lock directory")?;
+
+        let names = (1..=*count).map(|index| format!("{prefix}{index}")).collect::<Vec<_>>();
+        let open = |name: &str| {
+            OpenOptions::new()
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(self.build_users().join(name))
+                .context("allocate_build_user: open the build user's lock file")
+        };
+
+        let mut lease = None;
+
+        for name in &names {
+            let lock = open(name)?;
+
+            if flock(&lock, FlockOperation::NonBlockingLockExclusive).is_ok() {
+                lease = Some((name, lock));
+                break;
+            }
+        }
+
+        let (name, lock) = match lease {
+            Some(lease) => lease,
+            None => {
+                let name = names.first().ok_or_else(|| PackageManagerError::BuildUserNotFound(format!("{prefix}1")))?;
+                let lock = open(name)?;
+                flock(&lock, FlockOperation::LockExclusive).context("allocate_build_user: wait for a build user to be free")?;
+
+                (name, lock)
+            }
+        };
+
+        let user = User::from_name(name)
+            .context("allocate_build_user: look up the build user")?
+            .ok_or_else(|| PackageManagerError::BuildUserNotFound(name.clone()))?;
+
+        Ok(BuildUserLease {
+            user: BuildUser { name: user.name, uid: user.uid, gid: user.gid },
+            _lock: lock,
+        })
+    }
+}
+
+impl Sandbox {
+    /// Run a function inside of the sandbox and wait for it to finish.
+    /// This forks twice, once to enter the namespaces and once more to become PID 1 in the new PID namespace.
+    /// Every line the sandbox writes to stdout or stderr is passed to `output`.
+    pub fn run(&self, output: &mut (dyn FnMut(&str) + Send), build: impl FnOnce() -> Result<(), PackageManagerError>) -> Result<(), PackageManagerError> {
+        fs::create_dir_all(&self.root).context("sandbox: create the sandbox's root directory")?;
+
+        let (reader, writer) = io::pipe().context("sandbox: create the output pipe")?;
+        let flags = fcntl_getfl(&reader).context("sandbox: read the output pipe's flags")?;
+        fcntl_setfl(&reader, flags | OFlags::NONBLOCK).context("sandbox: make the output pipe non-blocking")?;
+        self.chown_writable(true)?;
+
+        // Memory and processes are limited by a cgroup where one can be created, and only approximated by rlimits otherwise.
+        let cgroup = if self.limits.memory.is_some() || self.limits.processes.is_some() {
+            Cgroup::create(&self.root, &self.limits)
+                .inspect_err(|err| {
+                    output(&format!(
+                        "==> Warning: could not create a cgroup for the build ({err}), falling back to rlimits which limit address space rather than memory and processes per user rather than per build"
+                    ))
+                })
+                .ok()
+        } else {
+            None
+        };
+
+        let result = match unsafe { fork().context("sandbox: fork process")? } {
+            ForkResult::Parent { child } => {
+                // Close the parent's copy of the write end, the reader only reaches EOF once every writer is gone.
+                drop(writer);
+
+                // Processes the build left behind can keep the write end open, so the reader is stopped once the build was waited for instead of at EOF.
+                let done = AtomicBool::new(false);
+
+                thread::scope(|scope| {
+                    scope.spawn(|| forward_output(reader, &done, output));
+
+                    let result = self.wait(child, cgroup.as_ref());
+                    done.store(true, Ordering::Release);
+
+                    result
+                })
+            }
+            ForkResult::Child => {
+                let status = (|| {
+                    drop(reader);
+                    dup2(writer.as_raw_fd(), io::stdout().as_raw_fd()).context("sandbox: redirect stdout to the output pipe")?;
+                    dup2(writer.as_raw_fd(), io::stderr().as_raw_fd()).context("sandbox: redirect stderr to the output pipe")?;
+                    drop(writer);
+
+                    setpgid(Pid::from_raw(0), Pid::from_raw(0)).context("sandbox: create a new process group")?;
+
+                    if let Some(cgroup) = &cgroup {
+                        cgroup.enter()?;
+                    }
+
+                    self.enter_namespaces()?;
+                    self.spawn_init(cgroup.is_some(), build)
+                })();
+
+                match status {
+                    Ok(status) => forward_status(status),
+                    Err(err) => exit_with(Err(err)),
+                }
+            }
+        };
+
+        if let Some(cgroup) = cgroup {
+            cgroup.remove();
+        }
+
+        self.chown_writable(false)?;
+        fs::remove_dir(&self.root).context("sandbox: remove the sandbox's root directory")?;
+
+        if let Some(output_size) = self.limits.output_size
+            && result.is_ok()
+            && disk_usage(&self.out).context("sandbox: measure the size of the output")? > output_size
+        {
+            return Err(PackageManagerError::OutputLimitExceeded(output_size));
+        }
+
+        result
+    }
+
+    /// Open an interactive nushell in the sandbox and wait for it to exit, for debugging a failed build.
+    /// The shell is set up exactly like a build and starts with the same variables declared,
+    /// but it keeps the terminal and isn't subject to the timeout or the cgroup.
+    pub fn shell(&self) -> Result<(), PackageManagerError> {
+        // The executable is opened on the host, inside the sandbox it's only reachable through this file descriptor.
+        let nu = File::open(self.config.nu_executable()?).context("sandbox: open the nushell executable")?;
+        let prelude = CString::new(self.script("")).map_err(|_| PackageManagerError::ScriptFailed("the sandbox's environment contains a NUL byte".into()))?;
+
+        fs::create_dir_all(&self.root).context("sandbox: create the sandbox's root directory")?;
+        self.chown_writable(true)?;
+
+        let result = match unsafe { fork().context("sandbox: fork process")? } {
+            ForkResult::Parent { child } => waitpid(child, None).context("sandbox: wait for the shell to exit").map(|_| ()),
+            ForkResult::Child => {
+                let status = self.enter_namespaces().and_then(|()| {
+                    self.spawn_init(false, || {
+                        // The environment was set up by `setup`, `--execute` declares the variables before the prompt shows up.
+                        let env = env::vars_os()
+                            .filter_map(|(key, value)| {
+                                let mut pair = key.into_vec();
+                                pair.push(b'=');
+                                pair.extend(value.into_vec());
+                                CString::new(pair).ok()
+                            })
+                            .collect::<Vec<_>>();
+
+                        let Err(err) = fexecve(nu.as_raw_fd(), &[c"nu", c"--no-config-file", c"--execute", prelude.as_c_str()], &env);
+                        Err(PackageManagerError::nix("sandbox: start nushell", err))
+                    })
+                });
+
+                match status {
+                    Ok(status) => forward_status(status),
+                    Err(err) => exit_with(Err(err)),
+                }
+            }
+        };
+
+        self.chown_writable(false)?;
+        fs::remove_dir(&self.root).context("sandbox: remove the sandbox's root directory")?;
+
+        result
+    }
+
+    /// Hand the directories the sandbox writes to over to its build user, or back to the calling user once it exits.
+    /// They are created by the calling user, so a build user couldn't write its outputs otherwise.
+    /// In a user namespace the calling user is root inside the sandbox, so nothing changes hands.
+    fn chown_writable(&self, lend: bool) -> Result<(), PackageManagerError> {
+        let Some(user) = self.user.as_ref().filter(|_| !self.config.user_namespace) else {
+            return Ok(());
+        };
+
+        let (uid, gid) = if lend { (user.uid, user.gid) } else { (geteuid(), getegid()) };
+
+        for dir in [&self.out, &self.build] {
+            chown_tree(dir, uid, gid).context(format!("sandbox: change the owner of '{}' to uid {uid}", dir.display()))?;
+        }
+
+        Ok(())
+    }
+
+    /// Wait for the sandbox to exit, killing its process group once the timeout is reached.
+    fn wait(&self, child: Pid, cgroup: Option<&Cgroup>) -> Result<(), PackageManagerError> {
+        let start = Instant::now();
+        let flags = self.limits.timeout.map(|_| WaitPidFlag::WNOHANG);
+
+        // The exit code, or the signal that killed the build.
+        let exit = loop {
+            match waitpid(child, flags).context("sandbox: wait for child to exit")? {
+                WaitStatus::Exited(_, code) => break Ok(code),
+                WaitStatus::Signaled(_, signal, _) => break Err(signal),
+                // Still running, stopped, continued or traced children haven't exited yet.
+                WaitStatus::StillAlive | WaitStatus::Stopped(..) | WaitStatus::Continued(_) | WaitStatus::PtraceEvent(..) | WaitStatus::PtraceSyscall(_) => {}
+            }
+
+            if let Some(timeout) = self.limits.timeout {
+                if start.elapsed() >= timeout {
+                    // Only the child itself is left to kill if its process group can't be killed.
+                    killpg(child, Signal::SIGKILL).or_else(|_| kill(child, Signal::SIGKILL)).context("sandbox: kill the timed out build")?;
+                    waitpid(child, None).context("sandbox: wait for the timed out build to exit")?;
+
+                    return Err(PackageManagerError::BuildTimedOut(timeout));
+                }
+
+                thread::sleep(WAIT_POLL_INTERVAL);
+            }
+        };
+
+        if exit != Ok(0)
+            && let Some(cgroup) = cgroup
+        {
+            if cgroup.event("memory.events", "oom_kill") > 0 {
+                return Err(PackageManagerError::MemoryLimitExceeded(self.limits.memory.unwrap_or_default()));
+            }
+
+            if cgroup.event("pids.events", "max") > 0 {
+                return Err(PackageManagerError::ProcessLimitExceeded(self.limits.processes.unwrap_or_default()));
+            }
+        }
+
+        match exit {
+            Ok(0) => Ok(()),
+            Ok(code) => Err(PackageManagerError::BuildFailed(code)),
+            Err(Signal::SIGXCPU) => Err(PackageManagerError::CpuLimitExceeded(self.limits.cpu.unwrap_or_default())),
+            Err(Signal::SIGXFSZ) => Err(PackageManagerError::OutputLimitExceeded(self.limits.output_size.unwrap_or_default())),
+            Err(signal) => Err(PackageManagerError::BuildKilled(signal)),
+        }
+    }
+
+    fn enter_namespaces(&self) -> Result<(), PackageManagerError> {
+        let mut flags = CloneFlags::CLONE_NEWNS | CloneFlags::CLONE_NEWPID | CloneFlags::CLONE_NEWUTS | CloneFlags::CLONE_NEWIPC;
+
+        if !self.network {
+            flags |= CloneFlags::CLONE_NEWNET;
+        }
+
+        if self.config.user_namespace {
+            let (uid, gid) = (geteuid(), getegid());
+
+            unshare(flags | CloneFlags::CLONE_NEWUSER).context("sandbox: unshare namespaces")?;
+
+            fs::write("/proc/self/setgroups", "deny").context("sandbox: deny setgroups in the user namespace")?;
+            fs::write("/proc/self/uid_map", format!("0 {uid} 1")).context("sandbox: write the user namespace's uid map")?;
+            fs::write("/proc/self/gid_map", format!("0 {gid} 1")).context("sandbox: write the user namespace's gid map")?;
+        } else {
+            unshare(flags).context("sandbox: unshare namespaces")?;
+        }
+
+        Ok(())
+    }
+
+    /// Fork into the new PID namespace and run the build there, returning how it exited.
+    /// Memory and process rlimits are only set when the build isn't in a cgroup that limits them properly.
+    fn spawn_init(&self, in_cgroup: bool, build: impl FnOnce() -> Result<(), PackageManagerError>) -> Result<WaitStatus, PackageManagerError> {
+        match unsafe { fork().context("sandbox: fork into the new PID namespace")? } {
+            ForkResult::Parent { child } => waitpid(child, None).context("sandbox: wait for the build to exit"),
+            ForkResult::Child => exit_with(self.setup(in_cgroup).and_then(|()| build())),
+        }
+    }
+
+    fn setup(&self, in_cgroup: bool) -> Result<(), PackageManagerError> {
+        let root = &self.root;
+
+        // Keep every mount made from here on out of the host's mount namespace.
+        mount(None::<&str>, "/", None::<&str>, MsFlags::MS_REC | MsFlags::MS_PRIVATE, None::<&str>).context("sandbox: make the mounts private")?;
+        mount(Some("tmpfs"), root, Some("tmpfs"), MsFlags::MS_NOSUID | MsFlags::MS_NODEV, Some("mode=0755")).context("sandbox: mount the tmpfs root")?;
+
+        for dir in ["out", "src", "build", "tmp", "proc", "dev", "store", ".old"] {
+            fs::create_dir(root.join(dir)).context("sandbox: create the root's directories")?;
+        }
+
+        bind(&self.out, root.join("out"), false)?;
+        bind(&self.src, root.join("src"), true)?;
+        bind(&self.build, root.join("build"), false)?;
+
+        if let Some(fetched) = &self.fetched {
+            fs::create_dir(root.join("fetched")).context("sandbox: create mountpoint for the fetched output")?;
+            bind(fetched, root.join("fetched"), true)?;
+        }
+
+        for dep in &self.deps {
+            let Some(name) = dep.file_name() else {
+                continue;
+            };
+
+            let target = root.join("store").join(name);
+            fs::create_dir(&target).context("sandbox: create mountpoint for dependency")?;
+            bind(dep, target, true)?;
+        }
+
+        if self.network {
+            for file in NETWORK_FILES.iter().map(Path::new).filter(|file| file.exists()) {
+                let target = root.join(file.strip_prefix("/").unwrap_or(file));
+                fs::create_dir_all(target.parent().unwrap_or(root)).context("sandbox: create the parent of a network file's mountpoint")?;
+
+                if file.is_dir() {
+                    fs::create_dir(&target).context("sandbox: create mountpoint for a network directory")?;
+                } else {
+                    File::create(&target).context("sandbox: create mountpoint for a network file")?;
+                }
+
+                bind(file, target, true)?;
+            }
+        }
+
+        for device in DEVICES {
+            let target = root.join("dev").join(device);
+            File::create(&target).context("sandbox: create mountpoint for device")?;
+            bind(Path::new("/dev").join(device), target, false)?;
+        }
+
+        mount(Some("tmpfs"), &root.join("tmp"), Some("tmpfs"), MsFlags::MS_NOSUID | MsFlags::MS_NODEV, None::<&str>).context("sandbox: mount /tmp")?;
+        mount(Some("proc"), &root.join("proc"), Some("proc"), MsFlags::MS_NOSUID | MsFlags::MS_NODEV | MsFlags::MS_NOEXEC, None::<&str>).context("sandbox: mount /proc")?;
+
+        pivot_root(root, &root.join(".old")).context("sandbox: pivot into the new root")?;
+        chdir("/").context("sandbox: enter the new root")?;
+        umount2("/.old", MntFlags::MNT_DETACH).context("sandbox: detach the old root")?;
+        fs::remove_dir("/.old").context("sandbox: remove the old root's mountpoint")?;
+
+        sethostname("sandbox").context("sandbox: set hostname")?;
+        chdir("/build").context("sandbox: enter the build directory")?;
+
+        for (key, value) in &self.env {
+            env::set_var(key, value);
+        }
+
+        self.set_rlimits(in_cgroup)?;
+
+        if let Some(user) = &self.user
+            && !self.config.user_namespace
+        {
+            setgroups(&[]).context(format!("sandbox: clear the supplementary groups of '{}'", user.name))?;
+            setgid(user.gid).context(format!("sandbox: set the group of '{}'", user.name))?;
+            setuid(user.uid).map_err(|_| PackageManagerError::SetUID)?;
+        }
+
+        Ok(())
+    }
+
+    /// Run a nushell script with the sandbox's environment, this must be called inside of the sandbox.
+    /// An error in the script fails the build, which makes the sandbox exit with a non-zero status.
+    pub fn eval(&self, engine: &Engine, script: &str) -> Result<(), PackageManagerError> {
+        engine.eval(&self.script(script)).map(|_| ()).map_err(|err| PackageManagerError::ScriptFailed(err.to_string()))
+    }
+
+    /// Prefix a nushell script with the sandbox's environment, both as `$env.<NAME>` and as `$<NAME>`.
+    pub fn script(&self, script: &str) -> String {
+        let mut prelude = String::new();
+
+        for (key, value) in &self.env {
+            let value = nu_string(value);
+            prelude.push_str(&format!("$env.{key} = {value}\nlet {key} = {value}\n"));
+        }
+
+        prelude + script
+    }
+
+    fn set_rlimits(&self, in_cgroup: bool) -> Result<(), PackageManagerError> {
+        let limits = &self.limits;
+
+        if let Some(cpu) = limits.cpu {
+            // The soft limit sends SIGXCPU, the hard limit a few seconds later sends SIGKILL.
+            setrlimit(Resource::RLIMIT_CPU, cpu.as_secs(), cpu.as_secs() + 5).context("sandbox: limit CPU time")?;
+        }
+
+        if let Some(memory) = limits.memory
+            && !in_cgroup
+        {
+            setrlimit(Resource::RLIMIT_AS, memory, memory).context("sandbox: limit memory")?;
+        }
+
+        if let Some(processes) = limits.processes
+            && !in_cgroup
+        {
+            setrlimit(Resource::RLIMIT_NPROC, processes, processes).context("sandbox: limit processes")?;
+        }
+
+        // The total size of the output is checked once the build exits, no single file can be bigger than it though.
+        if let Some(output_size) = limits.output_size {
+            setrlimit(Resource::RLIMIT_FSIZE, output_size, output_size).context("sandbox: limit output size")?;
+        }
+
+        Ok(())
+    }
+}
+
+/// A cgroup v2 that a single build runs in.
+#[derive(Debug)]
+struct Cgroup {
+    path: PathBuf,
+}
+
+impl Cgroup {
+    fn create(sandbox_root: &Path, limits: &Limits) -> Result<Self, PackageManagerError> {
+        let name = sandbox_root.file_name().map(|name| name.display().to_string()).unwrap_or_default();
+        let path = Path::new(CGROUP_ROOT).join(name);
+
+        fs::create_dir_all(CGROUP_ROOT).context("sandbox: create the cgroup subtree for builds")?;
+        fs::write(Path::new(CGROUP_ROOT).join("cgroup.subtree_control"), "+memory +pids").context("sandbox: enable the cgroup controllers for builds")?;
+        fs::create_dir_all(&path).context("sandbox: create the build's cgroup")?;
+        let cgroup = Self { path };
+
+        if let Some(memory) = limits.memory {
+            fs::write(cgroup.path.join("memory.max"), memory.to_string()).context("sandbox: limit the cgroup's memory")?;
+            fs::write(cgroup.path.join("memory.swap.max"), "0").context("sandbox: disable the cgroup's swap")?;
+        }
+
+        if let Some(processes) = limits.processes {
+            fs::write(cgroup.path.join("pids.max"), processes.to_string()).context("sandbox: limit the cgroup's processes")?;
+        }
+
+        Ok(cgroup)
+    }
+
+    /// Move the calling process into the cgroup.
+    fn enter(&self) -> Result<(), PackageManagerError> {
+        fs::write(self.path.join("cgroup.procs"), "0").context("sandbox: enter the build's cgroup")
+    }
+
+    /// Read a counter from one of the cgroup's event files.
+    fn event(&self, file: &str, key: &str) -> u64 {
+        fs::read_to_string(self.path.join(file))
+            .unwrap_or_default()
+            .lines()
+            .find_map(|line| line.strip_prefix(key)?.trim().parse().ok())
+            .unwrap_or_default()
+    }
+
+    fn remove(self) {
+        let _ = fs::remove_dir(self.path);
+    }
+}
+
+/// Quote a string for nushell as a raw string, so that nothing in it is escaped or interpolated.
+/// A raw string ends at a quote followed by as many `#` as it started with, so it uses one more than any such run in the string.
+fn nu_string(value: &str) -> String {
+    let hashes = (1..).find(|count| !value.contains(&format!("'{}", "#".repeat(*count)))).unwrap_or(1);
+    let hashes = "#".repeat(hashes);
+
+    format!("r{hashes}'{value}'{hashes}")
+}
+
+/// Bind mount a path, optionally read-only.
+fn bind(source: impl AsRef<Path>, target: impl AsRef<Path>, read_only: bool) -> Result<(), PackageManagerError> {
+    let (source, target) = (source.as_ref(), target.as_ref());
+
+    mount(Some(source), target, None::<&str>, MsFlags::MS_BIND | MsFlags::MS_REC, None::<&str>).context(format!("sandbox: bind '{}'", source.display()))?;
+
+    if read_only {
+        mount(None::<&str>, target, None::<&str>, MsFlags::MS_BIND | MsFlags::MS_REMOUNT | MsFlags::MS_RDONLY, None::<&str>)
+            .context(format!("sandbox: make '{}' read-only", source.display()))?;
+    }
+
+    Ok(())
+}
+
+/// Change the owner of a directory and everything in it, symlinks are changed themselves rather than followed.
+fn chown_tree(path: &Path, uid: Uid, gid: Gid) -> io::Result<()> {
+    lchown(path, Some(uid.as_raw()), Some(gid.as_raw()))?;
+
+    if fs::symlink_metadata(path)?.is_dir() {
+        for entry in fs::read_dir(path)? {
+            chown_tree(&entry?.path(), uid, gid)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Exit a forked child with the same status as the process it waited for, so it reaches the parent unchanged.
+/// Pass every line the sandbox writes to `output`, until the pipe is closed or is empty once `done` is set.
+fn forward_output(mut reader: PipeReader, done: &AtomicBool, output: &mut (dyn FnMut(&str) + Send)) {
+    let mut pending = Vec::new();
+    let mut buffer = [0; 4096];
+
+    loop {
+        match reader.read(&mut buffer) {
+            Ok(0) => break,
+            Ok(read) => {
+                pending.extend_from_slice(&buffer[..read]);
+
+                while let Some(end) = pending.iter().position(|&byte| byte == b'\n') {
+                    let line = pending.drain(..=end).collect::<Vec<_>>();
+                    output(String::from_utf8_lossy(&line[..end]).trim_end_matches('\r'));
+                }
+            }
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => {
+                if done.load(Ordering::Acquire) {
+                    break;
+                }
+
+                thread::sleep(WAIT_POLL_INTERVAL);
+            }
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+            Err(_) => break,
+        }
+    }
+
+    if !pending.is_empty() {
+        output(String::from_utf8_lossy(&pending).trim_end_matches('\r'));
+    }
+}
+
+fn forward_status(status: WaitStatus) -> ! {
+    match status {
+        WaitStatus::Exited(_, code) => process::exit(code),
+        WaitStatus::Signaled(_, signal, _) => {
+            let _ = raise(signal);
+            process::exit(128 + signal as i32);
+        }
+        _ => process::exit(1),
+    }
+}
+
+/// Exit a forked child, logging the error if there was one.
+fn exit_with(result: Result<(), PackageManagerError>) -> ! {
+    if let Err(err) = result {
+        error!("{err}");
+        process::exit(1);
+    }
+
+    process::exit(0);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{err, util::test::TempDir};
+
+    /// A sandbox over fresh directories in `dir`, running as the calling user.
+    pub(crate) fn sandbox(dir: &Path) -> Sandbox {
+        for name in ["out", "src", "build"] {
+            fs::create_dir_all(dir.join(name)).unwrap();
+        }
+
+        Sandbox {
+            root: dir.join("root"),
+            out: dir.join("out"),
+            src: dir.join("src"),
+            build: dir.join("build"),
+            deps: Vec::new(),
+            fetched: None,
+            env: vec![("out".into(), "/out".into())],
+            network: false,
+            user: None,
+            limits: Limits::default(),
+            config: SandboxConfig::default(),
+        }
+    }
+
+    // Namespaces can only be created by root.
+    #[test]
+    #[ignore = "requires root"]
+    fn builds_only_see_their_sandbox() {
+        let dir = TempDir::new();
+        let sandbox = sandbox(&dir);
+        fs::write(dir.join("src/input"), "hello").unwrap();
+
+        let mut lines = Vec::new();
+        let result = sandbox.run(&mut |line| lines.push(line.to_owned()), || {
+            let input = fs::read_to_string("/src/input").context("read the input")?;
+            fs::write("/out/output", input).context("write the output")?;
+
+            println!("{}", env::current_dir().unwrap().display());
+            println!("{}", fs::write("/src/input", "changed").is_err());
+            println!("{}", Path::new("/etc").exists());
+
+            Ok(())
+        });
+
+        result.unwrap();
+        assert_eq!(fs::read_to_string(dir.join("out/output")).unwrap(), "hello");
+        assert_eq!(fs::read_to_string(dir.join("src/input")).unwrap(), "hello");
+        assert_eq!(lines, ["/build", "true", "false"]);
+        assert!(!sandbox.root.exists());
+    }
+
+    #[test]
+    #[ignore = "requires root"]
+    fn failed_builds_are_reported() {
+        let dir = TempDir::new();
+        let result = sandbox(&dir).run(&mut |_| {}, || err!(PackageNotInstalled));
+
+        assert!(matches!(result, Err(PackageManagerError::BuildFailed(1))));
+    }
+
+    #[test]
+    #[ignore = "requires root"]
+    fn writable_dirs_are_lent_to_the_build_user() {
+        let dir = TempDir::new();
+        let mut sandbox = sandbox(&dir);
+        fs::create_dir(dir.join("out/bin")).unwrap();
+        fs::write(dir.join("out/bin/tool"), "").unwrap();
+
+        let nobody = User::from_name("nobody").unwrap().unwrap();
+        sandbox.user = Some(BuildUser {
+            name: nobody.name,
+            uid: nobody.uid,
+            gid: nobody.gid,
+        });
+        let owner = |path: &str| Uid::from_raw(std::os::unix::fs::MetadataExt::uid(&fs::metadata(dir.join(path)).unwrap()));
+
+        sandbox.chown_writable(true).unwrap();
+        assert_eq!([owner("out"), owner("out/bin/tool"), owner("build"), owner("src")], [nobody.uid, nobody.uid, nobody.uid, geteuid()]);
+
+        sandbox.chown_writable(false).unwrap();
+        assert_eq!([owner("out"), owner("out/bin/tool"), owner("build")], [geteuid(); 3]);
+    }
+
+    #[test]
+    fn package_limits_override_the_configured_ones() {
+        let config = Limits {
+            timeout: Some(Duration::from_secs(60)),
+            memory: Some(1024),
+            ..Default::default()
+        };
+        let package = Limits {
+            memory: Some(2048),
+            processes: Some(4),
+            ..Default::default()
+        };
+
+        let limits = package.or(&config);
+
+        assert_eq!(
+            (limits.timeout, limits.cpu, limits.memory, limits.processes),
+            (Some(Duration::from_secs(60)), None, Some(2048), Some(4))
+        );
+    }
+
+    #[test]
+    #[ignore = "requires root"]
+    fn the_output_size_limit_covers_the_whole_output() {
+        let dir = TempDir::new();
+        let mut sandbox = sandbox(&dir);
+        sandbox.limits.output_size = Some(1000);
+
+        let result = sandbox.run(&mut |_| {}, || {
+            for name in ["a", "b"] {
+                fs::write(Path::new("/out").join(name), [0; 600]).context("write an output")?;
+            }
+
+            Ok(())
+        });
+
+        assert!(matches!(result, Err(PackageManagerError::OutputLimitExceeded(1000))));
+    }
+
+    #[test]
+    #[ignore = "requires root"]
+    fn failing_scripts_fail_the_build() {
+        let dir = TempDir::new();
+        let sandbox = sandbox(&dir);
+        let engine = Engine::new();
+
+        let result = sandbox.run(&mut |_| {}, || sandbox.eval(&engine, "touch $\"($out)/before\"; error make { msg: 'boom' }; touch $\"($out)/after\""));
+
+        assert!(matches!(result, Err(PackageManagerError::BuildFailed(1))));
+        assert!(dir.join("out/before").exists() && !dir.join("out/after").exists());
+    }
+
+    #[test]
+    #[ignore = "requires root and a resolv.conf"]
+    fn networked_sandboxes_can_resolve_names() {
+        let dir = TempDir::new();
+        let mut sandbox = sandbox(&dir);
+        sandbox.network = true;
+
+        let mut lines = Vec::new();
+        let result = sandbox.run(&mut |line| lines.push(line.to_owned()), || {
+            println!("{}", fs::read_to_string("/etc/resolv.conf").is_ok());
+            println!("{}", fs::write("/etc/resolv.conf", "").is_err());
+
+            Ok(())
+        });
+
+        result.unwrap();
+        assert_eq!(lines, ["true", "true"]);
+    }
+
+    #[test]
+    fn output_stops_being_read_once_the_build_is_done() {
+        let (reader, mut writer) = io::pipe().unwrap();
+        fcntl_setfl(&reader, fcntl_getfl(&reader).unwrap() | OFlags::NONBLOCK).unwrap();
+        io::Write::write_all(&mut writer, b"first\r\nsecond\nunterminated").unwrap();
+
+        // The write end stays open, like it would with a process the build left behind.
+        let done = AtomicBool::new(true);
+        let mut lines = Vec::new();
+        forward_output(reader, &done, &mut |line| lines.push(line.to_owned()));
+
+        assert_eq!(lines, ["first", "second", "unterminated"]);
+        drop(writer);
+    }
+
+    #[test]
+    fn values_are_quoted_as_raw_strings() {
+        assert_eq!(nu_string("/store/foo-1.0.0"), "r#'/store/foo-1.0.0'#");
+        assert_eq!(nu_string("$(rm -rf /) \\u{41} \"é\""), "r#'$(rm -rf /) \\u{41} \"é\"'#");
+        assert_eq!(nu_string("it's a '# and a '##"), "r###'it's a '# and a '##'###");
+    }
+
+    #[test]
+    fn the_environment_is_declared_in_front_of_scripts() {
+        let dir = TempDir::new();
+        let mut sandbox = sandbox(&dir);
+        sandbox.env.push(("PKG_NAME".into(), "($x)".into()));
+
+        assert_eq!(
+            sandbox.script("ls"),
+            "$env.out = r#'/out'#\nlet out = r#'/out'#\n$env.PKG_NAME = r#'($x)'#\nlet PKG_NAME = r#'($x)'#\nls"
+        );
+    }
+}
@@ -0,0 +1,476 @@
+//! The native functions that package files can call on top of the ones `tl` provides.
+
+use crate::{
+    diagnostic::{render, SourceText},
+    package::{check_definition, definition_id, FieldError},
+    trace::Tracer,
+    version::Version,
+};
+use std::{
+    env, fs,
+    path::{Path, PathBuf},
+};
+use tl::{
+    object,
+    parser::parse,
+    runtime::{
+        types::{NativeFunction, Value},
+        Scope,
+    },
+    Source,
+};
+
+/// Environment variables that package files may read with `env`, on top of every variable starting with `PKG_`.
+const ALLOWED_ENV: &[&str] = &["HOME", "USER", "LANG", "TZ", "SOURCE_DATE_EPOCH"];
+
+/// Create the error a native function returns for invalid input.
+pub(crate) fn native_fn_error(message: impl Into<String>) -> Box<tl::Error> {
+    Box::new(tl::Error::new(tl::runtime::ErrorType::NativeFnError(message.into()), None))
+}
+
+/// Create the error for an invalid object passed to the `package` or `group` function.
+/// Invalid fields are pointed at where they are set, missing fields at the call they are missing from.
+fn definition_error(text: Option<&SourceText>, function: &str, data: &Value, err: FieldError) -> Box<tl::Error> {
+    let call = text.and_then(|text| text.find_call(function, definition_id(data).as_deref()));
+    let location = match (&err.field, text, call) {
+        (Some(field), Some(text), Some(call)) => text.find_from(call, &format!("{field} =")).or(Some(call)),
+        _ => call,
+    };
+
+    native_fn_error(render(text, location, &err.message, err.help))
+}
+
+/// Get a string argument of a native function.
+pub(crate) fn string_arg<'a>(args: &'a [Value], index: usize, function: &str) -> Result<&'a str, Box<tl::Error>> {
+    match args.get(index) {
+        Some(Value::String(string)) => Ok(string),
+        _ => Err(native_fn_error(format!("Argument {} of the `{function}` function must be a string", index + 1))),
+    }
+}
+
+/// Get a version argument of a native function.
+fn version_arg(args: &[Value], index: usize, function: &str) -> Result<Version, Box<tl::Error>> {
+    let version = string_arg(args, index, function)?;
+
+    Version::parse(version).ok_or_else(|| native_fn_error(format!("Argument {} of the `{function}` function, \"{version}\", is not a valid version", index + 1)))
+}
+
+/// Register every native function in a scope.
+/// `file` is the path of the file being evaluated, which `import` and `readFile` resolve relative paths against.
+pub(crate) fn register(scope: &mut Scope, tracer: &Tracer, file: Option<PathBuf>) {
+    register_imported(scope, tracer, file.into_iter().collect());
+}
+
+/// Register every native function in a scope evaluating the last of `imports`, each file in which imported the next one.
+fn register_imported(scope: &mut Scope, tracer: &Tracer, imports: Vec<PathBuf>) {
+    let dir = imports.last().and_then(|file| file.parent()).map(Path::to_path_buf);
+    let text = imports.last().and_then(|file| SourceText::open(file));
+
+    // Every call is traced before the function's body runs.
+    macro_rules! native_fn {
+        ($name:literal, $params:literal, |$args:ident| $body:expr) => {{
+            let tracer = tracer.clone();
+
+            scope.add_native_fn(
+                $name,
+                NativeFunction::Strict {
+                    params: $params,
+                    func: Box::new(move |$args| {
+                        tracer.call($name, $args.iter());
+                        $body
+                    }),
+                },
+            );
+        }};
+    }
+
+    // `package({ ... })` defines a package.
+    // Its input is checked here, so that an error is reported at the call instead of wherever the package ends up.
+    let package_text = text.clone();
+    native_fn!("package", 1, |args| {
+        let Some(data @ Value::Object(_)) = args.first() else {
+            return Err(native_fn_error("The `package` function requires an object as input"));
+        };
+
+        check_definition("Package", data).map_err(|err| definition_error(package_text.as_ref(), "package", data, err))?;
+
+        Ok(object!(kind = Value::String("Package".into()), data = data.clone()))
+    });
+
+    // `group({ ... })` defines a group of packages.
+    native_fn!("group", 1, |args| {
+        let Some(data @ Value::Object(_)) = args.first() else {
+            return Err(native_fn_error("The `group` function requires an object as input"));
+        };
+
+        check_definition("Group", data).map_err(|err| definition_error(text.as_ref(), "group", data, err))?;
+
+        Ok(object!(kind = Value::String("Group".into()), data = data.clone()))
+    });
+
+    // `fetchGit({ url = "...", rev = "..." })` is a `src` that is cloned from a git repository, `rev` is optional.
+    native_fn!("fetchGit", 1, |args| {
+        let Some(data @ Value::Object(_)) = args.first() else {
+            return Err(native_fn_error("The `fetchGit` function requires an object with a `url` and optionally a `rev` as input"));
+        };
+
+        Ok(object!(kind = Value::String("Git".into()), data = data.clone()))
+    });
+
+    // `fetchUrl({ url = "...", hash = "sha256:..." })` is a `src` that is downloaded, `hash` is optional.
+    native_fn!("fetchUrl", 1, |args| {
+        let Some(data @ Value::Object(_)) = args.first() else {
+            return Err(native_fn_error("The `fetchUrl` function requires an object with a `url` and optionally a `hash` as input"));
+        };
+
+        Ok(object!(kind = Value::String("Url".into()), data = data.clone()))
+    });
+
+    // `env("NAME")` is the value of an environment variable or null if it isn't set.
+    // Only the variables in `ALLOWED_ENV` and those starting with `PKG_` can be read, so that packages don't depend on the host by accident.
+    native_fn!("env", 1, |args| {
+        let name = string_arg(&args, 0, "env")?;
+
+        if !env_allowed(name) {
+            let allowed = ALLOWED_ENV.join(", ");
+            return Err(native_fn_error(format!("The environment variable \"{name}\" can't be read, only `PKG_*` and {allowed} can")));
+        }
+
+        Ok(env::var(name).map(Value::String).unwrap_or(Value::Null))
+    });
+
+    // `hostArch()` is the architecture of the host, such as "x86_64" or "aarch64".
+    native_fn!("hostArch", 0, |args| Ok(Value::String(env::consts::ARCH.into())));
+
+    // `semverAtLeast(version minimum)` is whether a version is the same as or newer than another one.
+    native_fn!("semverAtLeast", 2, |args| {
+        Ok(Value::Boolean(version_arg(&args, 0, "semverAtLeast")? >= version_arg(&args, 1, "semverAtLeast")?))
+    });
+
+    // `semverLess(a b)` is whether a version is older than another one.
+    native_fn!("semverLess", 2, |args| Ok(Value::Boolean(version_arg(&args, 0, "semverLess")? < version_arg(&args, 1, "semverLess")?)));
+
+    // `semverMax(a b)` is the newer of two versions.
+    native_fn!("semverMax", 2, |args| {
+        let (a, b) = (version_arg(&args, 0, "semverMax")?, version_arg(&args, 1, "semverMax")?);
+
+        Ok(args[if a >= b { 0 } else { 1 }].clone())
+    });
+
+    // `tl`'s own `readFile`, `maybe` and `toml` are replaced with equivalents here, so that their calls are traced like every other native function.
+    // `readFile("./Cargo.toml")` is the text of a file, resolved relative to the file being evaluated so that it doesn't depend on the working directory.
+    let read_dir = dir.clone();
+    native_fn!("readFile", 1, |args| {
+        let path = resolve(read_dir.as_deref(), string_arg(&args, 0, "readFile")?);
+
+        fs::read_to_string(&path)
+            .map(Value::String)
+            .map_err(|err| native_fn_error(format!("Could not read \"{}\": {err}", path.display())))
+    });
+
+    // `maybe(value default)` is the value, or the default if the value is null.
+    native_fn!("maybe", 2, |args| match args.first() {
+        Some(value) if *value != Value::Null => Ok(value.clone()),
+        _ => Ok(args.get(1).cloned().unwrap_or(Value::Null)),
+    });
+
+    // `toml(text)` is the value of a TOML document.
+    native_fn!("toml", 1, |args| {
+        let text = string_arg(&args, 0, "toml")?;
+
+        toml::from_str::<Value>(text).map_err(|err| native_fn_error(format!("Could not parse TOML: {err}")))
+    });
+
+    // `import("./common.tl")` is the value another file evaluates to, resolved relative to the importing file.
+    // The imported file gets the same native functions.
+    let import_tracer = tracer.clone();
+    scope.add_native_fn(
+        "import",
+        NativeFunction::Strict {
+            params: 1,
+            func: Box::new(move |args| {
+                import_tracer.call("import", args.iter());
+
+                let path = resolve(dir.as_deref(), string_arg(&args, 0, "import")?);
+                let path = path.canonicalize().map_err(|err| native_fn_error(format!("Could not read \"{}\": {err}", path.display())))?;
+
+                // A file that imports itself, directly or through other files, would never finish evaluating.
+                if imports.contains(&path) {
+                    let cycle = imports.iter().skip_while(|file| **file != path).chain([&path]).map(|file| file.display().to_string());
+                    return Err(native_fn_error(format!("Import cycle: {}", cycle.collect::<Vec<_>>().join(" -> "))));
+                }
+
+                let source = Source::from_path(&path).map_err(|err| native_fn_error(format!("Could not read \"{}\": {err}", path.display())))?;
+                let ast = parse(&source)?;
+                let mut scope = Scope::new(source, ast);
+                register_imported(&mut scope, &import_tracer, imports.iter().cloned().chain([path]).collect());
+
+                scope.eval()
+            }),
+        },
+    );
+}
+
+/// Whether package files may read an environment variable with `env`.
+fn env_allowed(name: &str) -> bool {
+    name.starts_with("PKG_") || ALLOWED_ENV.contains(&name)
+}
+
+/// Resolve a path given to a native function, relative paths are relative to the directory of the file being evaluated.
+fn resolve(dir: Option<&Path>, path: &str) -> PathBuf {
+    match dir {
+        Some(dir) if Path::new(path).is_relative() => dir.join(path),
+        _ => PathBuf::from(path),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        package::{Definition, Package, Src},
+        util::test::TempDir,
+    };
+    use prelude::logger::Log;
+
+    /// Write the given files and evaluate the first one as a package file, giving an error as its message.
+    fn eval(files: &[(&str, &str)]) -> (TempDir, Result<Definition, String>) {
+        let dir = TempDir::new();
+
+        for (path, contents) in files {
+            let path = dir.join(path);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, contents).unwrap();
+        }
+
+        let source = Source::from_path(dir.join(files[0].0)).unwrap();
+        let result = Package::eval(source).map_err(|err| err.to_string());
+
+        (dir, result)
+    }
+
+    /// Evaluate a package file that defines a single package.
+    fn package(text: &str) -> Package {
+        match eval(&[("package.tl", text)]).1 {
+            Ok(Definition::Package(package)) => package,
+            Ok(Definition::Group(group)) => panic!("expected a package, got the group \"{}\"", group.id),
+            Err(err) => panic!("{err}"),
+        }
+    }
+
+    /// Evaluate a file that isn't a package file, such as a single call of a native function.
+    fn value(text: &str) -> Result<Value, String> {
+        let dir = TempDir::new();
+        let path = dir.join("value.tl");
+        fs::write(&path, text).unwrap();
+
+        let source = Source::from_path(&path).unwrap();
+        let Ok(ast) = parse(&source) else {
+            panic!("\"{text}\" should parse");
+        };
+        let mut scope = Scope::new(source, ast);
+        register(&mut scope, &Tracer::default(), Some(path));
+
+        scope.eval().map_err(|err| Log::from(*err).to_string())
+    }
+
+    /// The message of the error a package file fails with.
+    fn error(text: &str) -> String {
+        match eval(&[("package.tl", text)]).1 {
+            Ok(_) => panic!("expected the package file to fail"),
+            Err(err) => err,
+        }
+    }
+
+    #[test]
+    fn only_allowed_environment_variables_can_be_read() {
+        for name in ["PKG_VERSION", "PKG_", "HOME", "SOURCE_DATE_EPOCH"] {
+            assert!(env_allowed(name), "{name}");
+        }
+
+        for name in ["PATH", "AWS_SECRET_ACCESS_KEY", "pkg_version", "HOMEDIR"] {
+            assert!(!env_allowed(name), "{name}");
+        }
+    }
+
+    #[test]
+    fn host_arch_is_the_architecture_of_the_host() {
+        let package = package(r#"package({ id = "foo" description = hostArch() src = "." build = "" install = "" })"#);
+
+        assert_eq!(package.description, env::consts::ARCH);
+    }
+
+    #[test]
+    fn imports_are_relative_to_the_importing_file() {
+        let (_dir, result) = eval(&[
+            (
+                "pkgs/foo/package.tl",
+                "let common = import(\"../../lib/common.tl\")\n\npackage({ id = \"foo\" version = common.version src = \".\" build = \"\" install = \"\" })\n",
+            ),
+            ("lib/common.tl", "import(\"./version.tl\")\n"),
+            ("lib/version.tl", "{ version = \"1.2.3\" }\n"),
+        ]);
+
+        assert!(matches!(result, Ok(Definition::Package(package)) if package.version == "1.2.3"));
+    }
+
+    #[test]
+    fn import_cycles_are_rejected() {
+        let (_dir, result) = eval(&[
+            (
+                "package.tl",
+                "let common = import(\"./a.tl\")\n\npackage({ id = \"foo\" version = common.version src = \".\" build = \"\" install = \"\" })\n",
+            ),
+            ("a.tl", "import(\"./b.tl\")\n"),
+            ("b.tl", "import(\"./a.tl\")\n"),
+        ]);
+
+        assert!(result.is_err_and(|err| err.contains("Import cycle") && err.contains("a.tl -> ") && err.contains("b.tl -> ")));
+    }
+
+    #[test]
+    fn groups_evaluate_to_their_members() {
+        let (_dir, result) = eval(&[(
+            "group.tl",
+            r#"group({
+    id = "tools"
+    packages = [
+        package({ id = "foo" src = "." build = "" install = "" })
+        "bar@1.0"
+        group({ id = "nested" packages = [ "baz" ] })
+    ]
+})
+"#,
+        )]);
+
+        let Ok(Definition::Group(group)) = result else {
+            panic!("expected a group");
+        };
+        assert_eq!((group.id.as_str(), group.name.as_str()), ("tools", "tools"));
+
+        let (packages, references) = group.members();
+        assert_eq!(packages.iter().map(|package| package.id.as_str()).collect::<Vec<_>>(), ["foo"]);
+        assert_eq!(references.iter().map(ToString::to_string).collect::<Vec<_>>(), ["bar@1.0", "baz"]);
+    }
+
+    #[test]
+    fn groups_with_invalid_members_are_rejected() {
+        let err = error(r#"group({ id = "tools" packages = [ "foo" 42 ] })"#);
+
+        assert!(err.contains("packages[1]"), "{err}");
+    }
+
+    #[test]
+    fn missing_fields_point_at_the_call() {
+        let err = error("let version = \"1.0\"\n\npackage({ id = \"foo\" version = version src = \".\" install = \"\" })\n");
+
+        assert!(err.contains("Missing field `build`"), "{err}");
+        assert!(err.contains("package.tl:3:1"), "{err}");
+    }
+
+    #[test]
+    fn invalid_fields_point_at_where_they_are_set() {
+        let err = error("package({\n    id = \"foo\"\n    authors = \"me\"\n    src = \".\"\n    build = \"\"\n    install = \"\"\n})\n");
+
+        assert!(err.contains("`authors`"), "{err}");
+        assert!(err.contains("package.tl:3:5"), "{err}");
+    }
+
+    #[test]
+    fn invalid_dependencies_name_the_field() {
+        let err = error(r#"package({ id = "foo" src = "." build = "" install = "" runtime_deps = [ "foo bar" ] })"#);
+
+        assert!(err.contains("runtime_deps[0]"), "{err}");
+    }
+
+    #[test]
+    fn fetch_git_sources_have_an_optional_rev() {
+        let with_rev = package(r#"package({ id = "foo" src = fetchGit({ url = "https://example.com/foo.git" rev = "v1.0" }) build = "" install = "" })"#);
+        let without_rev = package(r#"package({ id = "foo" src = fetchGit({ url = "https://example.com/foo.git" }) build = "" install = "" })"#);
+
+        assert!(matches!(with_rev.src, Src::Git { url, rev: Some(rev) } if url == "https://example.com/foo.git" && rev == "v1.0"));
+        assert!(matches!(without_rev.src, Src::Git { url, rev: None } if url == "https://example.com/foo.git"));
+    }
+
+    #[test]
+    fn fetch_url_sources_have_an_optional_hash() {
+        let with_hash = package(r#"package({ id = "foo" src = fetchUrl({ url = "https://example.com/foo.tar.gz" hash = "sha256:abc" }) build = "" install = "" })"#);
+        let without_hash = package(r#"package({ id = "foo" src = fetchUrl({ url = "https://example.com/foo.tar.gz" }) build = "" install = "" })"#);
+
+        assert!(matches!(with_hash.src, Src::Url { url, hash: Some(hash) } if url == "https://example.com/foo.tar.gz" && hash == "sha256:abc"));
+        assert!(matches!(without_hash.src, Src::Url { url, hash: None } if url == "https://example.com/foo.tar.gz"));
+    }
+
+    #[test]
+    fn semver_functions_compare_versions() {
+        let cases = [
+            (r#"semverAtLeast("1.2.0" "1.2.0")"#, Value::Boolean(true)),
+            (r#"semverAtLeast("1.2.0-rc.1" "1.2.0")"#, Value::Boolean(false)),
+            (r#"semverLess("1.9.0" "1.10.0")"#, Value::Boolean(true)),
+            (r#"semverLess("1.0.0" "1.0.0")"#, Value::Boolean(false)),
+            (r#"semverMax("1.10.0" "1.9.0")"#, Value::String("1.10.0".into())),
+            (r#"semverMax("0.9.0" "1.0.0")"#, Value::String("1.0.0".into())),
+        ];
+
+        for (text, expected) in cases {
+            assert!(value(text) == Ok(expected), "{text}");
+        }
+    }
+
+    #[test]
+    fn invalid_versions_are_rejected() {
+        for function in ["semverAtLeast", "semverLess", "semverMax"] {
+            let err = value(&format!(r#"{function}("1.0.0" "latest")"#)).unwrap_err();
+
+            assert!(err.contains(&format!(r#"Argument 2 of the `{function}` function, "latest", is not a valid version"#)), "{err}");
+        }
+    }
+
+    #[test]
+    fn files_are_read_relative_to_the_file_being_evaluated() {
+        let (_dir, result) = eval(&[
+            (
+                "pkgs/foo/package.tl",
+                "let common = import(\"../../lib/common.tl\")\n\npackage({ id = \"foo\" name = readFile(\"./name.txt\") description = common.description src = \".\" build = \"\" install = \"\" })\n",
+            ),
+            ("pkgs/foo/name.txt", "Foo"),
+            ("lib/common.tl", "{ description = readFile(\"./description.txt\") }\n"),
+            ("lib/description.txt", "A package"),
+        ]);
+
+        assert!(matches!(result, Ok(Definition::Package(package)) if package.name == "Foo" && package.description == "A package"));
+    }
+
+    #[test]
+    fn maybe_falls_back_only_on_null() {
+        assert!(value(r#"maybe(env("PKG_STDLIB_TEST_UNSET") "default")"#) == Ok(Value::String("default".into())));
+        assert!(value(r#"maybe("" "default")"#) == Ok(Value::String("".into())));
+    }
+
+    #[test]
+    fn toml_documents_are_parsed() {
+        let (_dir, result) = eval(&[
+            (
+                "package.tl",
+                "let cargo = toml(readFile(\"./Cargo.toml\"))\n\npackage({ id = cargo.package.name version = cargo.package.version authors = cargo.package.authors src = \".\" build = \"\" install = \"\" })\n",
+            ),
+            ("Cargo.toml", "[package]\nname = \"foo\"\nversion = \"1.2.3\"\nauthors = [\"Jane Doe\"]\n"),
+        ]);
+
+        let Ok(Definition::Package(package)) = result else {
+            panic!("expected a package");
+        };
+        assert_eq!((package.id.as_str(), package.version.as_str()), ("foo", "1.2.3"));
+        assert_eq!(package.authors, ["Jane Doe"]);
+
+        let err = value(r#"toml("name = ")"#).unwrap_err();
+        assert!(err.contains("Could not parse TOML"), "{err}");
+    }
+
+    #[test]
+    fn other_environment_variables_are_rejected() {
+        let err = error(r#"package({ id = "foo" description = env("PATH") src = "." build = "" install = "" })"#);
+
+        assert!(err.contains("The environment variable \"PATH\" can't be read"), "{err}");
+    }
+}
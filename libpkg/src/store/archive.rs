@@ -0,0 +1,261 @@
+use crate::{
+    err,
+    error::{Context, PackageManagerError},
+    event::Event,
+    hash::{hash_tree, hash_tree_without},
+    package::Package,
+    store::{check_err, journal::ignore_missing, runtime_closure, send, LockMode, StoreItem, Transaction, BOOKKEEPING_FILES},
+};
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::{self, File},
+    io::Read,
+    path::Path,
+    sync::mpsc::Sender,
+};
+
+/// Name of the archive entry holding the header, always the first entry.
+const HEADER_ENTRY: &str = "header";
+/// Directory in the archive holding the outputs of the store item.
+const OUTPUT_DIR: &str = "out";
+
+/// Metadata stored at the start of a package archive.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ArchiveHeader {
+    /// The package the store item was built from.
+    pub package: Package,
+    /// Hash of the outputs, as reported by the bundling root.
+    /// Installing never trusts it, the unpacked outputs are checked against the hash the caller expects.
+    pub hash: String,
+    /// Hash of the inputs the store item was built from, see [`input_hash`](crate::PackageManager::input_hash).
+    /// Binary caches serve the archive under this hash, it is checked when substituting.
+    pub input_hash: String,
+    /// Names of the store items in the runtime dependency closure of the package, excluding itself.
+    pub closure: Vec<String>,
+}
+
+impl StoreItem {
+    /// Hash the outputs of the item, leaving out the files kept by the package manager.
+    pub fn output_hash(&self) -> Result<String, PackageManagerError> {
+        hash_tree_without(&self.path, BOOKKEEPING_FILES).context(format!("output_hash: hash the outputs of '{}'", self.name()))
+    }
+}
+
+impl crate::PackageManager {
+    /// Export an installed package as a gzip compressed tar archive, returning its header.
+    pub fn bundle(&self, id: impl AsRef<str>, version: Option<&str>, dest: impl AsRef<Path>) -> Result<ArchiveHeader, PackageManagerError> {
+        let _lock = self.lock_store(LockMode::Shared, None)?;
+
+        let items = self.store_items()?;
+        let Some(item) = items.iter().find(|item| item.is_installed() && item.matches(id.as_ref(), version)) else {
+            return err!(PackageNotInstalled);
+        };
+        let Some(package) = self.read_store_item(item.path.clone())?.package else {
+            return err!(PackageNotInstalled);
+        };
+
+        let mut closure = runtime_closure(&items, [item]).into_iter().filter(|name| *name != item.name()).collect::<Vec<_>>();
+        closure.sort();
+
+        // Hash the source kept in the store, the package's own source may have changed or moved since it was built.
+        let kept_src = self.store().join("src").join(item.name());
+        let input_hash = if kept_src.exists() {
+            self.input_hash_with_src(&package, &kept_src)?
+        } else {
+            self.input_hash(&package)?
+        };
+
+        let header = ArchiveHeader {
+            hash: item.output_hash()?,
+            input_hash,
+            package,
+            closure,
+        };
+
+        let file = File::create(dest.as_ref()).context("bundle: create the archive")?;
+        let mut builder = tar::Builder::new(GzEncoder::new(file, Compression::default()));
+        builder.follow_symlinks(false);
+
+        let bytes = bincode::serialize(&header).context("bundle: serialize the archive header")?;
+        let mut entry = tar::Header::new_gnu();
+        entry.set_size(bytes.len() as u64);
+        entry.set_mode(0o644);
+        entry.set_cksum();
+        builder.append_data(&mut entry, HEADER_ENTRY, bytes.as_slice()).context("bundle: write the archive header")?;
+
+        for entry in fs::read_dir(&item.path).context("bundle: list the outputs of the store item")?.filter_map(Result::ok) {
+            let name = entry.file_name();
+
+            if BOOKKEEPING_FILES.iter().any(|file| name == *file) {
+                continue;
+            }
+
+            let target = Path::new(OUTPUT_DIR).join(&name);
+
+            if entry.file_type().context("bundle: read the type of an output")?.is_dir() {
+                builder.append_dir_all(&target, entry.path()).context("bundle: add an output directory to the archive")?;
+            } else {
+                builder.append_path_with_name(entry.path(), &target).context("bundle: add an output file to the archive")?;
+            }
+        }
+
+        builder.into_inner().context("bundle: finish the archive")?.finish().context("bundle: compress the archive")?;
+
+        Ok(header)
+    }
+
+    /// Install a package from an archive made by [`bundle`](Self::bundle) without building it, this must be ran in a separate thread.
+    /// The archive is only installed if its outputs match `hash`, the output hash that [`bundle`](Self::bundle) returned.
+    /// A mpsc sender must be given to send progress events.
+    /// This function requires root privileges.
+    pub fn install_archive(&self, archive: impl AsRef<Path>, hash: impl AsRef<str>, tx: &Sender<Event>) {
+        check_err!(tx, self.install_archive_inner(archive.as_ref(), hash.as_ref(), tx));
+    }
+
+    fn install_archive_inner(&self, archive: &Path, hash: &str, tx: &Sender<Event>) -> Result<(), PackageManagerError> {
+        let _lock = self.lock_store(LockMode::Exclusive, Some(tx))?;
+
+        self.unpack_archive(archive, hash, None, tx)
+    }
+
+    /// Unpack an archive into the store if its outputs match `hash`, the store must be locked exclusively.
+    /// If a package is expected along with its input hash, the archive is rejected unless it was built from the same package and inputs.
+    pub(crate) fn unpack_archive(&self, archive: &Path, hash: &str, expected: Option<(&Package, &str)>, tx: &Sender<Event>) -> Result<(), PackageManagerError> {
+        let mut tar = tar::Archive::new(GzDecoder::new(File::open(archive).context("install_archive: open the archive")?));
+        let mut entries = tar.entries().context("install_archive: read the archive")?;
+
+        let header = match entries.next() {
+            Some(Ok(mut entry)) if entry.path().is_ok_and(|path| path == Path::new(HEADER_ENTRY)) => {
+                let mut bytes = Vec::new();
+                entry.read_to_end(&mut bytes).context("install_archive: read the archive header")?;
+
+                bincode::deserialize::<ArchiveHeader>(&bytes).context("install_archive: deserialize the archive header")?
+            }
+            _ => return err!(InvalidArchive("the archive does not start with a header".into())),
+        };
+
+        if let Some((expected, input_hash)) = expected {
+            if expected.id != header.package.id || expected.version != header.package.version {
+                return err!(InvalidArchive(format!("expected \"{}@{}\" but the archive contains \"{}@{}\"", expected.id, expected.version, header.package.id, header.package.version)));
+            }
+
+            if input_hash != header.input_hash {
+                return err!(InvalidArchive(format!("built from the inputs {} instead of {input_hash}", header.input_hash)));
+            }
+        }
+
+        let package_full_id = format!("{}-{}", header.package.id, header.package.version);
+        let path = self.store().join(&package_full_id);
+
+        if path.exists() {
+            return err!(PackageAlreadyInstalled);
+        }
+
+        let installed = self.store_items()?.into_iter().filter(StoreItem::is_installed).map(|item| item.name()).collect::<Vec<_>>();
+
+        if let Some(missing) = header.closure.iter().find(|name| !installed.contains(name)) {
+            return err!(MissingDependency(missing.clone()));
+        }
+
+        send!(tx, AllocatingInStore);
+
+        self.journaled(Transaction::install(&self.store(), package_full_id.clone()), || {
+            let scratch = self.scratch().join(&package_full_id);
+            let out_dir = scratch.join(OUTPUT_DIR);
+
+            ignore_missing(fs::remove_dir_all(&scratch)).context("install_archive: remove leftover scratch directory")?;
+            fs::create_dir_all(&out_dir).context("install_archive: create the output directory")?;
+
+            for entry in entries {
+                let mut entry = entry.context("install_archive: read an archive entry")?;
+
+                if !entry.path().is_ok_and(|path| path.starts_with(OUTPUT_DIR)) {
+                    return err!(InvalidArchive("the archive contains entries outside of the outputs".into()));
+                }
+
+                entry.unpack_in(&scratch).context("install_archive: unpack an archive entry")?;
+            }
+
+            let actual = hash_tree(&out_dir).context("install_archive: hash the unpacked outputs")?;
+
+            if actual != hash {
+                return Err(PackageManagerError::ArchiveHashMismatch { expected: hash.to_owned(), actual });
+            }
+
+            fs::rename(&out_dir, &path).context("install_archive: move the outputs into the store")?;
+            File::create(path.join("links")).context("install_archive: create empty links file for the package")?;
+            self.write_store_metadata(&path, &header.package)?;
+            fs::remove_dir_all(&scratch).context("install_archive: remove the scratch directory")?;
+
+            self.add_to_current_generation(&package_full_id)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        error::PackageManagerError,
+        event::Event,
+        util::test::{add_item, package, root},
+    };
+    use std::{fs, sync::mpsc};
+
+    #[test]
+    fn archives_round_trip_between_roots() {
+        let (_source_root, source) = root(Some(&["foo-1.0.0"]));
+        let foo = package("foo", "1.0.0", &[]);
+        add_item(&source, &foo, true);
+
+        let item = source.store().join("foo-1.0.0");
+        fs::create_dir_all(item.join("bin")).unwrap();
+        fs::write(item.join("bin/foo"), "#!/bin/sh\n").unwrap();
+        fs::write(item.join("README"), "foo").unwrap();
+
+        let archive = source.store().join("foo.pkg");
+        let header = source.bundle("foo", None, &archive).unwrap();
+        assert!(header.closure.is_empty());
+
+        let (_dest_root, dest) = root(Some(&[]));
+        let (tx, rx) = mpsc::channel();
+        dest.install_archive(&archive, &header.hash, &tx);
+        drop(tx);
+
+        for event in rx {
+            assert!(!matches!(event, Event::Error(_)), "{event:?}");
+        }
+
+        let unpacked = dest.store().join("foo-1.0.0");
+        assert_eq!(fs::read_to_string(unpacked.join("bin/foo")).unwrap(), "#!/bin/sh\n");
+        assert_eq!(fs::read_to_string(unpacked.join("README")).unwrap(), "foo");
+        assert_eq!(dest.read_store_item(unpacked.clone()).unwrap().package.unwrap().id, "foo");
+        assert_eq!(dest.get_store_item("foo", "1.0.0").unwrap().unwrap().output_hash().unwrap(), header.hash);
+        assert!(fs::read_to_string(dest.generations().join("current/manifest")).unwrap().lines().any(|line| line == "foo-1.0.0"));
+    }
+
+    #[test]
+    fn archives_are_checked_against_the_expected_hash() {
+        let (_source_root, source) = root(Some(&["foo-1.0.0"]));
+        add_item(&source, &package("foo", "1.0.0", &[]), true);
+        let item = source.store().join("foo-1.0.0");
+        fs::write(item.join("README"), "foo").unwrap();
+
+        let archive = source.store().join("foo.pkg");
+        let expected = source.bundle("foo", None, &archive).unwrap().hash;
+
+        // A tampered archive whose header was rewritten to match its contents.
+        fs::write(item.join("README"), "tampered").unwrap();
+        let tampered = source.bundle("foo", None, &archive).unwrap().hash;
+        assert_ne!(tampered, expected);
+
+        let (_dest_root, dest) = root(Some(&[]));
+        let (tx, rx) = mpsc::channel();
+        dest.install_archive(&archive, &expected, &tx);
+        drop(tx);
+
+        let mismatch = |event: Event| matches!(event, Event::Error(PackageManagerError::ArchiveHashMismatch { expected: hash, .. }) if hash == expected);
+        assert!(rx.into_iter().any(mismatch));
+        assert!(!dest.store().join("foo-1.0.0").exists());
+    }
+}
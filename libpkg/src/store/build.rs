@@ -0,0 +1,71 @@
+use crate::{
+    error::{Context, PackageManagerError},
+    event::Event,
+    package::Package,
+    store::{check_err, send, LockMode},
+    util::create_unique_dir,
+};
+use fs_extra::dir::CopyOptions;
+use std::{env, fs, path::PathBuf, sync::mpsc::Sender};
+
+/// Options for building a package without installing it.
+#[derive(Debug, Default, Clone)]
+pub struct BuildOptions {
+    /// Directory to move the build output into, defaults to a new directory in the system's temporary directory.
+    /// It is created if it doesn't exist and must be empty otherwise.
+    pub output: Option<PathBuf>,
+    /// Leave the scratch directory of the build around for debugging.
+    pub keep_sandbox: bool,
+    /// Open an interactive nushell in the sandbox of a stage that failed, before the build is cleaned up.
+    pub shell_on_failure: bool,
+}
+
+impl crate::PackageManager {
+    /// Build a package without installing it, this must be ran in a separate thread.
+    /// The build runs in the same sandbox as [`install`](Self::install) but never creates a store item or a generation,
+    /// once it succeeded an [`Event::Built`] is sent with the path to the output.
+    /// This function requires root privileges.
+    pub fn build(&self, package: Package, options: BuildOptions, tx: &Sender<Event>) {
+        check_err!(tx, self.build_inner(package, options, tx));
+    }
+
+    fn build_inner(&self, mut package: Package, options: BuildOptions, tx: &Sender<Event>) -> Result<(), PackageManagerError> {
+        // Dependencies are only read from the store.
+        let _lock = self.lock_store(LockMode::Shared, Some(tx))?;
+        self.apply_overlays(&mut package)?;
+
+        let package_full_id = format!("{}-{}", package.id, package.version);
+
+        // Fail before building instead of mixing the output with whatever is already there.
+        if let Some(output) = &options.output
+            && fs::read_dir(output).is_ok_and(|mut entries| entries.next().is_some())
+        {
+            return Err(PackageManagerError::OutputNotEmpty(output.clone()));
+        }
+
+        let output = self.build_in_scratch(
+            &package,
+            &package_full_id,
+            None,
+            &format!("{}.build", package.version),
+            &options,
+            |out_dir| {
+                let output = match &options.output {
+                    Some(output) => {
+                        fs::create_dir_all(output).context("build: create the output directory")?;
+                        output.clone()
+                    }
+                    None => create_unique_dir(env::temp_dir(), &format!("pkg-build-{package_full_id}")).context("build: create the output directory")?,
+                };
+                fs_extra::dir::move_dir(out_dir, &output, &CopyOptions::new().content_only(true)).context("build: move the build output")?;
+
+                Ok(output)
+            },
+            tx,
+        )?;
+
+        send!(tx, Built(output));
+
+        Ok(())
+    }
+}
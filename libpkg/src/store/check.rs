@@ -0,0 +1,47 @@
+use crate::{
+    err,
+    error::{Context, PackageManagerError},
+    event::Event,
+    hash::diff_trees,
+    package::Package,
+    store::{check_err, send, BuildOptions, LockMode, BOOKKEEPING_FILES},
+};
+use std::sync::mpsc::Sender;
+
+impl crate::PackageManager {
+    /// Rebuild an installed package in a fresh sandbox and compare the output with its store item, this must be ran in a separate thread.
+    /// Every path that differs is sent as an [`Event::OutputDiffers`], the store item itself is left untouched.
+    /// This function requires root privileges.
+    pub fn check(&self, package: Package, tx: &Sender<Event>) {
+        check_err!(tx, self.check_inner(package, tx));
+    }
+
+    fn check_inner(&self, mut package: Package, tx: &Sender<Event>) -> Result<(), PackageManagerError> {
+        // The store item and the dependencies are only read, so other readers can run alongside the rebuild.
+        let _lock = self.lock_store(LockMode::Shared, Some(tx))?;
+        self.apply_overlays(&mut package)?;
+
+        let package_full_id = format!("{}-{}", package.id, package.version);
+        let path = self.store().join(&package_full_id);
+
+        if !path.exists() {
+            return err!(PackageNotInstalled);
+        }
+
+        let differences = self.build_in_scratch(
+            &package,
+            &package_full_id,
+            None,
+            &format!("{}.check", package.version),
+            &BuildOptions::default(),
+            |out_dir| diff_trees(&path, out_dir, BOOKKEEPING_FILES).context("check: compare the rebuilt output with the store item"),
+            tx,
+        )?;
+
+        for difference in differences {
+            send!(tx, OutputDiffers(difference));
+        }
+
+        Ok(())
+    }
+}
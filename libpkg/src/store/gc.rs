@@ -1,8 +1,38 @@
-use crate::error::{Context, Result};
-use std::{fs, io, path::Path};
+use crate::{
+    error::{Context, Result},
+    generations::GenerationId,
+    store::{runtime_closure, LockMode, StoreItem, Transaction},
+    util::disk_usage,
+};
+use std::{
+    collections::HashSet,
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+/// The outcome of a garbage collection.
+#[derive(Debug, Default)]
+pub struct GcReport {
+    /// Generations that were pruned by the retention policy, or would be pruned on a dry run.
+    pub pruned: Vec<GenerationId>,
+    /// Paths that were removed, or would be removed on a dry run.
+    pub removed: Vec<PathBuf>,
+    /// Number of bytes that were freed, or would be freed on a dry run.
+    pub freed: u64,
+}
 
 impl crate::PackageManager {
-    pub fn store_gc(&self) -> Result<()> {
+    /// Prune the generations outside of the retention policy, then delete every store item and source copy that isn't reachable from the remaining generations.
+    /// The manifest of every generation is treated as a root, and the runtime dependencies of every reachable item are followed.
+    /// With `dry_run` nothing is deleted, but the returned report still lists what would have been.
+    /// This function requires root privileges.
+    pub fn store_gc(&self, dry_run: bool) -> Result<GcReport> {
+        let _lock = self.lock_store(if dry_run { LockMode::Shared } else { LockMode::Exclusive }, None)?;
+
+        self.store_gc_inner(dry_run)
+    }
+
+    fn store_gc_inner(&self, dry_run: bool) -> Result<GcReport> {
         /// Returns the amount of dead symlinks removed from the given directory.
         fn remove_dead_symlinks(path: impl AsRef<Path>) -> io::Result<usize> {
             let mut count = 0;
@@ -20,25 +50,94 @@ impl crate::PackageManager {
             Ok(count)
         }
 
-        let garbage = fs::read_to_string(self.store().join("garbage"))
-            .unwrap_or_default()
-            .lines()
-            .filter(|s| !s.trim().is_empty())
-            .map(|s| self.root.join(s))
-            .collect::<Vec<_>>();
-
-        for path in garbage {
-            if path.join("links").exists() {
-                // Invalid entry, links would have been removed if the package should've been garbage collected.
-                continue;
+        let mut report = GcReport {
+            pruned: self.generations_to_prune(&self.gc_policy()?)?,
+            ..Default::default()
+        };
+
+        if !dry_run {
+            for id in &report.pruned {
+                self.delete_generation(*id)?;
+            }
+        }
+
+        let items = self.store_items()?;
+        let reachable = self.store_reachable(&items, &report.pruned)?;
+
+        let mut garbage = items.iter().filter(|item| !reachable.contains(&item.name())).map(|item| item.path.clone()).collect::<Vec<_>>();
+
+        for entry in fs::read_dir(self.store().join("src")).context("store_gc: list the sources in the store")?.filter_map(Result::ok) {
+            let name = entry.file_name().display().to_string();
+
+            // Fetched outputs are kept alongside the source of the item they were fetched for.
+            if !reachable.contains(name.strip_suffix(".fetch").unwrap_or(&name)) {
+                garbage.push(entry.path());
             }
+        }
+
+        for path in &garbage {
+            report.freed += disk_usage(path).context(format!("store_gc: get the size of '{}'", path.display()))?;
+        }
+
+        if !dry_run {
+            self.journaled(Transaction::Sweep { paths: garbage.clone() }, || {
+                for path in &garbage {
+                    fs::remove_dir_all(path).context(format!("store_gc: remove unreachable path '{}'", path.display()))?;
+                }
+
+                Ok(())
+            })?;
+        }
+
+        report.removed = garbage;
 
-            fs::remove_dir_all(&path).context(format!("store_gc: remove path '{}' found in garbage tracker", path.display()))?;
+        if !dry_run {
+            remove_dead_symlinks(self.root.join("bin")).context("store_gc: remove dead symlinks from /bin")?;
+            remove_dead_symlinks(self.root.join("lib")).context("store_gc: remove dead symlinks from /lib")?;
         }
 
-        remove_dead_symlinks(self.root.join("bin")).context("store_gc: remove dead symlinks from /bin")?;
-        remove_dead_symlinks(self.root.join("lib")).context("store_gc: remove dead symlinks from /lib")?;
+        Ok(report)
+    }
+
+    /// Mark the names of every store item reachable from the manifests of the generations that aren't being pruned.
+    /// A legacy generation without a manifest roots every installed item, since which items it uses isn't known.
+    fn store_reachable(&self, items: &[StoreItem], pruned: &[GenerationId]) -> Result<HashSet<String>> {
+        let generations = self.list_generations()?.into_iter().filter(|generation| !pruned.contains(&generation.id)).collect::<Vec<_>>();
+        let legacy = generations.iter().any(|generation| generation.legacy);
+        let roots = generations.into_iter().flat_map(|generation| generation.packages).collect::<HashSet<_>>();
+
+        Ok(runtime_closure(items, items.iter().filter(|item| roots.contains(&item.name()) || (legacy && item.is_installed()))))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::util::test::{add_item, package, root};
+    use std::collections::HashSet;
+
+    #[test]
+    fn reachable_follows_runtime_dependencies_from_manifests() {
+        let (_root, pm) = root(Some(&["app-1.0.0"]));
+        add_item(&pm, &package("lib", "1.0.0", &[]), true);
+        add_item(&pm, &package("app", "1.0.0", &["lib@1.0.0"]), true);
+        add_item(&pm, &package("lib", "2.0.0", &[]), true);
+        add_item(&pm, &package("orphan", "1.0.0", &[]), false);
+
+        let items = pm.store_items().unwrap();
+
+        assert_eq!(pm.store_reachable(&items, &[]).unwrap(), HashSet::from(["app-1.0.0".into(), "lib-1.0.0".into()]));
+        assert_eq!(pm.store_reachable(&items, &[1]).unwrap(), HashSet::new());
+    }
+
+    #[test]
+    fn legacy_generations_root_every_installed_item() {
+        let (_root, pm) = root(None);
+        add_item(&pm, &package("app", "1.0.0", &["lib"]), true);
+        add_item(&pm, &package("lib", "1.0.0", &[]), false);
+        add_item(&pm, &package("orphan", "1.0.0", &[]), false);
+
+        let items = pm.store_items().unwrap();
 
-        Ok(())
+        assert_eq!(pm.store_reachable(&items, &[]).unwrap(), HashSet::from(["app-1.0.0".into(), "lib-1.0.0".into()]));
     }
 }
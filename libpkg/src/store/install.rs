@@ -2,22 +2,19 @@ use crate::{
     err,
     error::{Context, PackageManagerError},
     event::Event,
-    package::{Package, Src},
-    store::{check_err, send, LOCK_POLL_INTERVAL, SANDBOX_UID},
+    hash::hash_tree,
+    package::{Group, Package, Src},
+    sandbox::Sandbox,
+    store::{check_err, journal::ignore_missing, send, BuildOptions, LockMode, StoreItem, Transaction},
+    util::create_unique_dir,
 };
 use fs_extra::dir::{CopyOptions, TransitProcessResult};
-use nix::{
-    sys::wait::waitpid,
-    unistd::{chroot, fork, setuid, ForkResult},
-};
-use prelude::logger::info;
 use std::{
     env,
     fs::{self, File},
-    path::Path,
+    path::{Path, PathBuf},
     process,
     sync::mpsc::Sender,
-    thread,
 };
 
 impl crate::PackageManager {
@@ -25,27 +22,58 @@ impl crate::PackageManager {
     /// A mpsc sender must be given to send progress events.
     /// This function requires root privileges.
     pub fn install(&self, package: Package, tx: &Sender<Event>) {
-        let result = self.install_inner(package, tx);
+        check_err!(tx, self.install_inner(package, tx));
+    }
 
-        check_err!(tx, self.store_set_immutable(true));
-        check_err!(tx, result);
+    fn install_inner(&self, mut package: Package, tx: &Sender<Event>) -> Result<(), PackageManagerError> {
+        let _lock = self.lock_store(LockMode::Exclusive, Some(tx))?;
+        self.apply_overlays(&mut package)?;
+
+        self.install_locked(package, tx)
     }
 
-    fn install_inner(&self, package: Package, tx: &Sender<Event>) -> Result<(), PackageManagerError> {
-        // Check if store is locked
-        if !self.store_is_immutable()? {
-            send!(tx, AwaitingUnlock);
+    /// Install every member of a group into the current generation, this must be ran in a separate thread.
+    /// Members are installed in order under a single lock, members that are already installed are only added to the generation.
+    /// If any member fails, the members installed so far are removed again and the generation is left as it was.
+    /// A mpsc sender must be given to send progress events.
+    /// This function requires root privileges.
+    pub fn install_group(&self, group: Group, tx: &Sender<Event>) {
+        check_err!(tx, self.install_group_inner(group, tx));
+    }
 
-            while !self.store_is_immutable()? {
-                thread::sleep(LOCK_POLL_INTERVAL);
-            }
+    fn install_group_inner(&self, group: Group, tx: &Sender<Event>) -> Result<(), PackageManagerError> {
+        let _lock = self.lock_store(LockMode::Exclusive, Some(tx))?;
+        let id = group.id.clone();
+        let (mut packages, references) = group.members();
 
-            send!(tx, Unlocked);
+        for package in &mut packages {
+            self.apply_overlays(package)?;
         }
 
-        send!(tx, AllocatingInStore);
+        let members = packages.iter().map(|package| format!("{}-{}", package.id, package.version)).collect::<Vec<_>>();
+        let manifest = self.migrated_current_generation()?.packages;
+
+        // Either every member ends up in the current generation or none of them do.
+        self.journaled(Transaction::install_group(&self.store(), id, members.clone(), manifest), || {
+            // Referenced packages can't be fetched from a repository yet, so they must already be in the store.
+            for item in self.resolve_deps(&references.iter().collect::<Vec<_>>())? {
+                self.add_to_current_generation(item.name())?;
+            }
 
-        self.store_set_immutable(false)?;
+            for (package, package_full_id) in packages.into_iter().zip(members) {
+                match self.install_locked(package, tx) {
+                    Err(PackageManagerError::PackageAlreadyInstalled) => self.add_to_current_generation(package_full_id)?,
+                    result => result?,
+                }
+            }
+
+            Ok(())
+        })
+    }
+
+    /// Install a package, the store must be locked exclusively and the overlays already applied.
+    fn install_locked(&self, package: Package, tx: &Sender<Event>) -> Result<(), PackageManagerError> {
+        send!(tx, AllocatingInStore);
 
         let package_full_id = format!("{}-{}", package.id, package.version);
         let path = self.store().join(&package_full_id);
@@ -54,55 +82,379 @@ impl crate::PackageManager {
             return err!(PackageAlreadyInstalled);
         }
 
-        // Initialize the build environment.
-        fs::create_dir_all(&path).context("install: create directory for the build env of the package")?;
-        fs::create_dir_all(path.join("bin")).context("install: create bin directory for the build env of the package")?;
-        fs::create_dir_all(path.join("lib")).context("install: create lib directory for the build env of the package")?;
-        File::create(path.join("links")).context("install: create empty links file for the package")?;
+        if self.substitute(&package, tx)? {
+            return Ok(());
+        }
+
+        self.journaled(Transaction::install(&self.store(), package_full_id.clone()), || {
+            self.build_in_scratch(
+                &package,
+                &package_full_id,
+                Some(&path),
+                &package.version,
+                &BuildOptions::default(),
+                |out_dir| self.move_into_store(&package, out_dir, &path),
+                tx,
+            )?;
+            self.add_to_current_generation(&package_full_id)
+        })
+    }
+
+    /// Find the path to the `src` field, paths are relative to the directory of the package file.
+    pub(crate) fn resolve_src(&self, package: &Package) -> Result<PathBuf, PackageManagerError> {
+        match &package.src {
+            Src::Path(src) => {
+                let path = match package.path.as_deref().and_then(Path::parent) {
+                    _ if src.is_absolute() => src.clone(),
+                    Some(dir) => dir.join(src),
+                    None => return err!(LocalPathOnRemotePackage),
+                };
 
-        // Find the path to the `src` field.
-        let src = match package.src {
-            Src::Path(src) => match src {
-                _ if src.is_absolute() => src,
-                _ if let Some(package_path) = package.path => {
-                    let joined = package_path.join(src);
-                    joined.canonicalize().unwrap_or(joined)
+                match path.canonicalize() {
+                    Ok(path) => Ok(path),
+                    Err(_) => err!(MissingSource(path)),
                 }
-                _ => return err!(LocalPathOnRemotePackage),
-            },
-            Src::Git(_) => unimplemented!("fetch source from git repository"),
-        };
+            }
+            // TODO: Fetch sources from git repositories and URLs
+            Src::Git { .. } | Src::Url { .. } => err!(UnsupportedSource(package.src.to_string())),
+        }
+    }
+
+    /// Move what the install stage wrote to `$out` into the store and write the item's bookkeeping files.
+    fn move_into_store(&self, package: &Package, out_dir: &Path, path: &Path) -> Result<(), PackageManagerError> {
+        fs::rename(out_dir, path).context("install: move the output into the store")?;
+        File::create(path.join("links")).context("install: create empty links file for the package")?;
+        self.write_store_metadata(path, package)
+    }
+
+    /// Build a package in a scratch directory, check its expected outputs, then hand what the install stage wrote to `$out` to `finish`.
+    /// When building a store item its source and fetched output are kept in the store, and the build log is copied into it
+    /// if the build and `finish` succeeded. Otherwise nothing is written to the store.
+    /// The build log is named `log_name`, see [`BuildLog`](super::log::BuildLog).
+    /// Only `keep_sandbox` and `shell_on_failure` are used from the options.
+    pub(crate) fn build_in_scratch<T>(
+        &self,
+        package: &Package,
+        package_full_id: &str,
+        item: Option<&Path>,
+        log_name: &str,
+        options: &BuildOptions,
+        finish: impl FnOnce(&Path) -> Result<T, PackageManagerError>,
+        tx: &Sender<Event>,
+    ) -> Result<T, PackageManagerError> {
+        let config = self.sandbox_config()?;
+
+        // The debug shell runs a nushell from the host, so its absence has to be reported before anything is built rather than once a stage failed.
+        if options.shell_on_failure {
+            config.nu_executable()?;
+        }
+
+        let src = self.resolve_src(package)?;
 
         // Copy the source
         let prefix = Path::new("/store/src");
-        if !(src.starts_with(prefix) && src.components().count() > prefix.components().count()) {
-            fs_extra::dir::copy_with_progress(&src, self.store().join("src").join(&package_full_id), &CopyOptions::new().overwrite(true), |progress| {
+        let src_dir = if item.is_some() && !(src.starts_with(prefix) && src.components().count() > prefix.components().count()) {
+            let dest = self.store().join("src").join(package_full_id);
+
+            fs_extra::dir::copy_with_progress(&src, &dest, &CopyOptions::new().overwrite(true), |progress| {
                 send!(tx, CopySrcProgress(progress.copied_bytes, progress.total_bytes));
                 TransitProcessResult::OverwriteAll
             })
             .context("install: copy source of package to store")?;
-        }
 
-        let nu_engine = self.nu_engine.clone();
+            dest
+        } else if src.starts_with(prefix) {
+            self.root.join(src.strip_prefix("/").unwrap_or(&src))
+        } else {
+            src
+        };
+
+        // Initialize the build environment, the working directory starts out as a copy of the source.
+        // Store items are only built with the store locked exclusively, other builds can run alongside each other.
+        let scratch = match item {
+            Some(_) => {
+                let scratch = self.scratch().join(package_full_id);
+                ignore_missing(fs::remove_dir_all(&scratch)).context("install: remove leftover scratch directory")?;
+                scratch
+            }
+            None => create_unique_dir(self.scratch(), package_full_id).context("install: create the scratch directory")?,
+        };
+        let (build_dir, out_dir) = (scratch.join("build"), scratch.join("out"));
+
+        fs::create_dir_all(&build_dir).context("install: create the build directory")?;
+        fs::create_dir_all(&out_dir).context("install: create the output directory")?;
+        fs_extra::dir::copy(&src_dir, &build_dir, &CopyOptions::new().content_only(true)).context("install: copy source into the build directory")?;
+
+        let build_user = if config.user_namespace { None } else { Some(self.allocate_build_user(&config)?) };
+        let deps = self.resolve_deps(&package.build_deps.iter().chain(&package.runtime_deps).collect::<Vec<_>>())?;
+
+        let mut env = vec![
+            ("PKG_ID".to_owned(), package.id.clone()),
+            ("PKG_NAME".to_owned(), package.name.clone()),
+            ("PKG_VERSION".to_owned(), package.version.clone()),
+            ("out".to_owned(), "/out".to_owned()),
+            ("src".to_owned(), "/src".to_owned()),
+            ("build".to_owned(), "/build".to_owned()),
+        ];
+        env.extend(dep_vars(&deps)?);
+
+        let sandbox_root = env::temp_dir().join(format!("pkg-sandbox-{}-{}", process::id(), scratch.file_name().unwrap_or_default().to_string_lossy()));
+        let sandbox = |out: &Path, build: &Path, fetched: Option<PathBuf>, network: bool| Sandbox {
+            root: sandbox_root.clone(),
+            out: out.to_path_buf(),
+            src: src_dir.clone(),
+            build: build.to_path_buf(),
+            deps: deps.iter().map(|dep| dep.path.clone()).collect(),
+            env: env.clone(),
+            fetched,
+            network,
+            user: build_user.as_ref().map(|lease| lease.user.clone()),
+            limits: package.limits.or(&config.limits),
+            config: config.clone(),
+        };
+
+        let mut log = self.create_build_log(&package.id, log_name)?;
+        let mut output = |line: &str| {
+            log.line(line);
+            let _ = tx.send(Event::BuildLog(line.to_owned()));
+        };
 
-        match unsafe { fork().context("install: fork process")? } {
-            ForkResult::Parent { child } => {
-                waitpid(child, None).context("install: wait for fork to exit")?;
+        // Drop into a shell in the sandbox of a failed stage, its error is still returned once the shell exits.
+        let debug_on_failure = |sandbox: &Sandbox, result: Result<(), PackageManagerError>| {
+            if result.is_err() && options.shell_on_failure {
+                send!(tx, DebugShell);
+                sandbox.shell()?;
             }
-            ForkResult::Child => {
-                chroot(&path).context("install: enter sandbox")?;
-                env::set_current_dir("/").context("install: set up sandbox")?;
 
-                setuid(SANDBOX_UID).map_err(|_| PackageManagerError::SetUID)?;
+            result
+        };
+
+        let result = (|| {
+            // Fetch with network access, then only expose the output once it matches the expected hash.
+            // The fetch stage works in its own copy of the source, anything it leaves outside `$out` isn't checked and never reaches the build.
+            let fetched = match &package.fetch {
+                Some(fetch) => {
+                    let fetched = match item {
+                        Some(_) => self.store().join("src").join(format!("{package_full_id}.fetch")),
+                        None => scratch.join("fetched"),
+                    };
+                    // Whatever an earlier attempt left behind would end up in the hash.
+                    ignore_missing(fs::remove_dir_all(&fetched)).context("install: remove the output of an earlier fetch")?;
+                    fs::create_dir_all(&fetched).context("install: create directory for the fetched output")?;
+
+                    let fetch_dir = scratch.join("fetch");
+                    fs::create_dir_all(&fetch_dir).context("install: create the fetch stage's working directory")?;
+                    fs_extra::dir::copy(&src_dir, &fetch_dir, &CopyOptions::new().content_only(true)).context("install: copy source into the fetch stage's working directory")?;
+
+                    output("==> Running the fetch stage");
+                    let sandbox = sandbox(&fetched, &fetch_dir, None, true);
+                    let result = sandbox.run(&mut output, || sandbox.eval(&self.nu_engine, &fetch.script));
+                    debug_on_failure(&sandbox, result)?;
 
-                info!("In sandbox: {src:?}");
+                    let actual = hash_tree(&fetched).context("install: hash the fetched output")?;
 
-                nu_engine.eval("touch /hello; ls /");
+                    if actual != fetch.hash {
+                        fs::remove_dir_all(&fetched).context("install: remove the mismatched fetched output")?;
+                        return Err(PackageManagerError::FetchHashMismatch { expected: fetch.hash.clone(), actual });
+                    }
 
-                process::exit(0);
+                    Some(fetched)
+                }
+                None => None,
+            };
+
+            output("==> Running the build and install stages");
+            let sandbox = sandbox(&out_dir, &build_dir, fetched, false);
+            let result = sandbox.run(&mut output, || {
+                sandbox.eval(&self.nu_engine, &package.build)?;
+                sandbox.eval(&self.nu_engine, &package.install)
+            });
+            debug_on_failure(&sandbox, result)?;
+
+            let missing = package
+                .expected_output
+                .iter()
+                .filter(|file| fs::symlink_metadata(out_dir.join(file.strip_prefix("/").unwrap_or(file))).is_err())
+                .cloned()
+                .collect::<Vec<_>>();
+
+            if !missing.is_empty() {
+                return Err(PackageManagerError::MissingOutput(missing));
             }
+
+            finish(&out_dir)
+        })();
+
+        log.finish(item.filter(|_| result.is_ok()))?;
+
+        if options.keep_sandbox {
+            send!(tx, ScratchKept(scratch));
+        } else {
+            fs::remove_dir_all(&scratch).context("install: remove the scratch directory")?;
         }
 
-        Ok(())
+        result
+    }
+}
+
+/// Name of the variable holding the store path of a dependency, for example `PKG_DEP_LIBFOO` for `libfoo`.
+fn dep_var(id: &str) -> String {
+    let id = id.chars().map(|c| if c.is_ascii_alphanumeric() { c.to_ascii_uppercase() } else { '_' }).collect::<String>();
+
+    format!("PKG_DEP_{id}")
+}
+
+/// The variables holding the store paths of the dependencies, failing if two dependencies would share a variable.
+fn dep_vars(deps: &[StoreItem]) -> Result<Vec<(String, String)>, PackageManagerError> {
+    let mut vars = Vec::<(String, String)>::new();
+
+    for dep in deps {
+        let (var, path) = (dep_var(&dep.id), format!("/store/{}", dep.name()));
+
+        if let Some((_, other)) = vars.iter().find(|(other, _)| *other == var) {
+            let other = other.trim_start_matches("/store/").to_owned();
+            return err!(DependencyVariableCollision(other, dep.name(), var));
+        }
+
+        vars.push((var, path));
+    }
+
+    Ok(vars)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        package::{Definition, Dependency, Fetch, Member},
+        util::test::{add_item, package, root, TempDir},
+    };
+    use std::sync::mpsc;
+
+    #[test]
+    fn only_the_output_is_moved_into_the_store() {
+        let (_root, pm) = root(Some(&[]));
+        let scratch = pm.scratch().join("foo-1.0.0");
+        fs::create_dir_all(scratch.join("out/bin")).unwrap();
+        fs::create_dir_all(scratch.join("build")).unwrap();
+        fs::write(scratch.join("out/bin/foo"), "").unwrap();
+        fs::write(scratch.join("build/foo.o"), "").unwrap();
+
+        let path = pm.store().join("foo-1.0.0");
+        pm.move_into_store(&package("foo", "1.0.0", &[]), &scratch.join("out"), &path).unwrap();
+
+        let item = pm.get_store_item("foo", "1.0.0").unwrap().unwrap();
+        assert!(item.is_installed());
+        assert_eq!(item.package.unwrap().id, "foo");
+        assert!(path.join("bin/foo").exists());
+        assert!(!path.join("foo.o").exists() && !path.join("build").exists());
+    }
+
+    #[test]
+    fn dependencies_get_distinct_variables() {
+        let (_root, pm) = root(Some(&[]));
+        add_item(&pm, &package("foo-bar", "1.0.0", &[]), true);
+        add_item(&pm, &package("baz", "2.0.0", &[]), true);
+        add_item(&pm, &package("foo_bar", "1.0.0", &[]), true);
+
+        let item = |id| pm.get_store_item(id, "1.0.0").unwrap().unwrap();
+        let vars = dep_vars(&[item("foo-bar"), pm.get_store_item("baz", "2.0.0").unwrap().unwrap()]).unwrap();
+
+        assert_eq!(
+            vars,
+            [
+                ("PKG_DEP_FOO_BAR".to_owned(), "/store/foo-bar-1.0.0".to_owned()),
+                ("PKG_DEP_BAZ".to_owned(), "/store/baz-2.0.0".to_owned())
+            ]
+        );
+        assert!(matches!(
+            dep_vars(&[item("foo-bar"), item("foo_bar")]),
+            Err(PackageManagerError::DependencyVariableCollision(first, second, var)) if first == "foo-bar-1.0.0" && second == "foo_bar-1.0.0" && var == "PKG_DEP_FOO_BAR"
+        ));
+    }
+
+    #[test]
+    fn groups_are_rolled_back_when_a_member_fails() {
+        let (_root, pm) = root(Some(&["foo-1.0.0"]));
+        add_item(&pm, &package("foo", "1.0.0", &[]), true);
+        add_item(&pm, &package("baz", "1.0.0", &[]), true);
+
+        // `bar` has a relative source but no package file, so it fails after `baz` was added to the generation.
+        let group = Group {
+            id: "group".into(),
+            name: "group".into(),
+            description: String::new(),
+            packages: vec![
+                Member::Reference(Dependency { id: "baz".into(), version: None }),
+                Member::Definition(Definition::Package(package("bar", "1.0.0", &[]))),
+            ],
+        };
+
+        let (tx, rx) = mpsc::channel();
+        pm.install_group(group, &tx);
+        drop(tx);
+
+        assert!(rx.into_iter().any(|event| matches!(event, Event::Error(PackageManagerError::LocalPathOnRemotePackage))));
+        assert_eq!(pm.current_generation().unwrap().packages, ["foo-1.0.0"]);
+        assert!(pm.store().join("baz-1.0.0").exists());
+        assert!(!pm.store().join("bar-1.0.0").exists());
+        assert_eq!(fs::read_dir(pm.journal()).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn sources_are_relative_to_the_package_file() {
+        let (root, pm) = root(Some(&[]));
+        fs::create_dir_all(root.join("pkgs/foo/src")).unwrap();
+
+        let mut foo = package("foo", "1.0.0", &[]);
+        foo.path = Some(root.join("pkgs/foo/package.tl"));
+        foo.src = Src::Path("src".into());
+        assert_eq!(pm.resolve_src(&foo).unwrap(), root.join("pkgs/foo/src").canonicalize().unwrap());
+
+        foo.src = Src::Path("missing".into());
+        assert!(matches!(pm.resolve_src(&foo), Err(PackageManagerError::MissingSource(path)) if path == root.join("pkgs/foo/missing")));
+    }
+
+    #[test]
+    #[ignore = "requires root"]
+    fn only_the_fetched_output_reaches_the_build() {
+        let (root, pm) = root(Some(&[]));
+        fs::create_dir_all(pm.config().join("system")).unwrap();
+        fs::write(pm.config().join("system/sandbox.tl"), "{ user_namespace = true }").unwrap();
+        fs::create_dir_all(root.join("src")).unwrap();
+
+        let expected = TempDir::new();
+        fs::write(expected.join("file"), "fetched").unwrap();
+
+        let mut foo = package("foo", "1.0.0", &[]);
+        foo.src = Src::Path(root.join("src"));
+        foo.fetch = Some(Fetch {
+            script: "'leaked' | save leaked; 'fetched' | save $\"($out)/file\"".into(),
+            hash: hash_tree(&*expected).unwrap(),
+        });
+        foo.build = "if ('leaked' | path exists) { error make { msg: 'the fetch stage leaked into the build' } }".into();
+        foo.install = "cp /fetched/file $\"($out)/file\"".into();
+
+        let (tx, _rx) = mpsc::channel();
+        let result = pm.build_in_scratch(&foo, "foo-1.0.0", None, "1.0.0", &BuildOptions::default(), |out| Ok(fs::read_to_string(out.join("file")).unwrap()), &tx);
+
+        assert_eq!(result.unwrap(), "fetched");
+    }
+
+    #[test]
+    fn a_missing_debug_shell_fails_before_building() {
+        let (_root, pm) = root(Some(&[]));
+        fs::create_dir_all(pm.config().join("system")).unwrap();
+        fs::write(pm.config().join("system/sandbox.tl"), "{ shell = \"/nonexistent/nu\" }").unwrap();
+
+        let options = BuildOptions {
+            shell_on_failure: true,
+            ..Default::default()
+        };
+        let (tx, _rx) = mpsc::channel();
+        let result = pm.build_in_scratch(&package("foo", "1.0.0", &[]), "foo-1.0.0", None, "1.0.0", &options, |_| Ok(()), &tx);
+
+        assert!(matches!(result, Err(PackageManagerError::NushellNotFound)));
+        assert!(!pm.scratch().exists() || fs::read_dir(pm.scratch()).unwrap().next().is_none());
     }
 }
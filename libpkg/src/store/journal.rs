@@ -0,0 +1,263 @@
+use crate::{
+    error::{Context, PackageManagerError},
+    store::read_links,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    fmt::{self, Display},
+    fs, io,
+    path::{Path, PathBuf},
+    process,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// A store operation recorded in the journal before it starts, so it can be recovered if it's interrupted.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum Transaction {
+    /// Installing a store item, rolled back if interrupted.
+    /// Only the source copy and fetched output that didn't exist before the install are removed on rollback.
+    Install { item: String, created_src: bool, created_fetch: bool },
+    /// Installing the members of a group, rolled back as a whole if any of them fails or the install is interrupted.
+    /// Only the store items that didn't exist before are removed, and the current generation gets its previous manifest back.
+    InstallGroup { group: String, created: Vec<String>, manifest: Vec<String> },
+    /// Removing store items from the symlinks and the current generation, completed if interrupted.
+    Remove { items: Vec<String> },
+    /// Deleting unreachable paths from the store, completed if interrupted.
+    Sweep { paths: Vec<PathBuf> },
+}
+
+impl Transaction {
+    /// The install of a store item, recording which of its source copy and fetched output it will create.
+    pub(crate) fn install(store: &Path, item: String) -> Self {
+        let src = store.join("src");
+
+        Self::Install {
+            created_src: !src.join(&item).exists(),
+            created_fetch: !src.join(format!("{item}.fetch")).exists(),
+            item,
+        }
+    }
+
+    /// The install of a group's members into a current generation that lists `manifest`, recording which store items it will create.
+    pub(crate) fn install_group(store: &Path, group: String, members: Vec<String>, manifest: Vec<String>) -> Self {
+        Self::InstallGroup {
+            group,
+            created: members.into_iter().filter(|item| !store.join(item).exists()).collect(),
+            manifest,
+        }
+    }
+}
+
+impl Display for Transaction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Install { item, .. } => write!(f, "install of \"{item}\""),
+            Self::InstallGroup { group, .. } => write!(f, "install of the group \"{group}\""),
+            Self::Remove { items } => write!(f, "removal of {}", items.iter().map(|item| format!("\"{item}\"")).collect::<Vec<_>>().join(", ")),
+            Self::Sweep { paths } => write!(f, "garbage collection of {} paths", paths.len()),
+        }
+    }
+}
+
+impl crate::PackageManager {
+    /// Recover every incomplete transaction found in the journal, returning them.
+    /// The store must be locked exclusively.
+    pub(crate) fn recover_journal(&self) -> Result<Vec<Transaction>, PackageManagerError> {
+        let journal = self.journal();
+
+        if !journal.exists() {
+            return Ok(Vec::new());
+        }
+
+        let mut entries = fs::read_dir(&journal)
+            .context("recover_journal: list the entries in the journal")?
+            .filter_map(Result::ok)
+            .map(|entry| entry.path())
+            .collect::<Vec<_>>();
+        entries.sort();
+
+        let mut recovered = Vec::new();
+
+        for entry in entries {
+            let transaction = serde_json::from_slice::<Transaction>(&fs::read(&entry).context("recover_journal: read journal entry")?)
+                .context(format!("recover_journal: deserialize journal entry '{}'", entry.display()))?;
+
+            self.recover_transaction(&transaction)?;
+            fs::remove_file(&entry).context("recover_journal: remove recovered journal entry")?;
+
+            recovered.push(transaction);
+        }
+
+        Ok(recovered)
+    }
+
+    /// Run an operation through the journal.
+    /// The transaction is recorded before the operation starts and recovered right away if the operation fails.
+    pub(crate) fn journaled<T>(&self, transaction: Transaction, operation: impl FnOnce() -> Result<T, PackageManagerError>) -> Result<T, PackageManagerError> {
+        let entry = self.journal_begin(&transaction)?;
+        let result = operation();
+
+        if result.is_err() {
+            self.recover_transaction(&transaction)?;
+        }
+
+        fs::remove_file(entry).context("journaled: remove completed journal entry")?;
+
+        result
+    }
+
+    fn journal_begin(&self, transaction: &Transaction) -> Result<PathBuf, PackageManagerError> {
+        fs::create_dir_all(self.journal()).context("journal_begin: create the journal directory")?;
+
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).map(|time| time.as_nanos()).unwrap_or_default();
+        let entry = self.journal().join(format!("{timestamp}-{}", process::id()));
+
+        let bytes = serde_json::to_vec(transaction).context("journal_begin: serialize transaction")?;
+        fs::write(&entry, bytes).context("journal_begin: write journal entry")?;
+
+        Ok(entry)
+    }
+
+    fn recover_transaction(&self, transaction: &Transaction) -> Result<(), PackageManagerError> {
+        match transaction {
+            Transaction::Install { item, created_src, created_fetch } => {
+                let path = self.store().join(item);
+
+                // The metadata of a partial install may be incomplete, so only its links are read.
+                for link in read_links(&path) {
+                    ignore_missing(fs::remove_file(self.root.join(link))).context("recover_transaction: remove symlinks of partial install")?;
+                }
+
+                ignore_missing(fs::remove_dir_all(&path)).context("recover_transaction: remove partially installed store item")?;
+
+                if *created_src {
+                    ignore_missing(fs::remove_dir_all(self.store().join("src").join(item))).context("recover_transaction: remove source of partial install")?;
+                }
+
+                if *created_fetch {
+                    ignore_missing(fs::remove_dir_all(self.store().join("src").join(format!("{item}.fetch")))).context("recover_transaction: remove fetched output of partial install")?;
+                }
+
+                ignore_missing(fs::remove_dir_all(self.scratch().join(item))).context("recover_transaction: remove scratch directory of partial install")?;
+                self.remove_from_current_generation(item)?;
+            }
+            Transaction::InstallGroup { created, manifest, .. } => {
+                for item in created {
+                    let path = self.store().join(item);
+
+                    for link in read_links(&path) {
+                        ignore_missing(fs::remove_file(self.root.join(link))).context("recover_transaction: remove symlinks of a group member")?;
+                    }
+
+                    ignore_missing(fs::remove_dir_all(&path)).context("recover_transaction: remove store item of a group member")?;
+                }
+
+                self.set_current_manifest(manifest.clone())?;
+            }
+            Transaction::Remove { items } => {
+                for item in items {
+                    let item = self.read_store_item(self.store().join(item))?;
+                    self.unlink_store_item(&item)?;
+                    self.remove_from_current_generation(item.name())?;
+                }
+            }
+            Transaction::Sweep { paths } => {
+                for path in paths {
+                    ignore_missing(fs::remove_dir_all(path)).context(format!("recover_transaction: remove unreachable path '{}'", path.display()))?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Treat a missing file as success, recovering a transaction must be idempotent.
+pub(crate) fn ignore_missing(result: io::Result<()>) -> io::Result<()> {
+    match result {
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(()),
+        result => result,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        err,
+        util::test::{add_item, package, root},
+    };
+
+    #[test]
+    fn interrupted_installs_are_rolled_back() {
+        let (_root, pm) = root(Some(&["foo-1.0.0"]));
+        let src = pm.store().join("src");
+        fs::create_dir_all(src.join("foo-1.0.0")).unwrap();
+
+        // The source copy existed before the install, the fetched output is created by it.
+        let transaction = Transaction::install(&pm.store(), "foo-1.0.0".into());
+        pm.journal_begin(&transaction).unwrap();
+        add_item(&pm, &package("foo", "1.0.0", &[]), true);
+        fs::create_dir_all(src.join("foo-1.0.0.fetch")).unwrap();
+
+        let recovered = pm.recover_journal().unwrap();
+
+        assert!(matches!(recovered.as_slice(), [Transaction::Install { item, created_src: false, created_fetch: true }] if item == "foo-1.0.0"));
+        assert!(!pm.store().join("foo-1.0.0").exists());
+        assert!(src.join("foo-1.0.0").exists());
+        assert!(!src.join("foo-1.0.0.fetch").exists());
+        assert!(pm.current_generation().unwrap().packages.is_empty());
+        assert_eq!(fs::read_dir(pm.journal()).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn interrupted_group_installs_are_rolled_back() {
+        let (_root, pm) = root(Some(&["foo-1.0.0"]));
+        add_item(&pm, &package("foo", "1.0.0", &[]), true);
+
+        // `foo` was already installed, `bar` was installed by the group before it was interrupted.
+        let transaction = Transaction::install_group(&pm.store(), "group".into(), vec!["foo-1.0.0".into(), "bar-1.0.0".into()], vec!["foo-1.0.0".into()]);
+        pm.journal_begin(&transaction).unwrap();
+        add_item(&pm, &package("bar", "1.0.0", &[]), true);
+        pm.add_to_current_generation("bar-1.0.0").unwrap();
+
+        let recovered = pm.recover_journal().unwrap();
+
+        assert!(matches!(recovered.as_slice(), [Transaction::InstallGroup { created, .. }] if created == &["bar-1.0.0"]));
+        assert!(pm.store().join("foo-1.0.0").exists());
+        assert!(!pm.store().join("bar-1.0.0").exists());
+        assert_eq!(pm.current_generation().unwrap().packages, ["foo-1.0.0"]);
+    }
+
+    #[test]
+    fn interrupted_sweeps_are_completed() {
+        let (_root, pm) = root(Some(&[]));
+        add_item(&pm, &package("foo", "1.0.0", &[]), false);
+        add_item(&pm, &package("bar", "1.0.0", &[]), false);
+
+        let paths = vec![pm.store().join("foo-1.0.0"), pm.store().join("bar-1.0.0")];
+        pm.journal_begin(&Transaction::Sweep { paths: paths.clone() }).unwrap();
+        fs::remove_dir_all(&paths[0]).unwrap();
+
+        assert_eq!(pm.recover_journal().unwrap().len(), 1);
+        assert!(!paths[1].exists());
+
+        // Recovering again finds nothing left to do.
+        assert!(pm.recover_journal().unwrap().is_empty());
+    }
+
+    #[test]
+    fn failed_operations_are_recovered_right_away() {
+        let (_root, pm) = root(Some(&[]));
+
+        let result = pm.journaled(Transaction::install(&pm.store(), "foo-1.0.0".into()), || {
+            add_item(&pm, &package("foo", "1.0.0", &[]), false);
+            pm.add_to_current_generation("foo-1.0.0")?;
+            err!(PackageNotInstalled)
+        });
+
+        assert!(matches!(result, Err(PackageManagerError::PackageNotInstalled)));
+        assert!(!pm.store().join("foo-1.0.0").exists());
+        assert!(pm.current_generation().unwrap().packages.is_empty());
+    }
+}
@@ -0,0 +1,208 @@
+use crate::{
+    config::eval_config,
+    error::{Context, PackageManagerError},
+    event::Event,
+    store::Transaction,
+};
+use rustix::{
+    fs::{FlockOperation, IFlags, flock, ioctl_getflags, ioctl_setflags},
+    io::Errno,
+};
+use serde::Deserialize;
+use std::{
+    fs::{self, File, OpenOptions},
+    io::Write,
+    path::{Path, PathBuf},
+    process,
+    sync::mpsc::Sender,
+};
+
+/// Name of the lock file inside the store.
+const LOCK_FILE: &str = "lock";
+
+/// Store settings, read from `config/system/store.tl`.
+#[derive(Debug, Default, Deserialize)]
+pub struct StoreConfig {
+    /// Keep the store immutable with the `FS_IMMUTABLE_FL` inode flag while no writer holds the lock.
+    /// This is only a hardening layer and requires a filesystem that supports it.
+    #[serde(default)]
+    pub immutable: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LockMode {
+    /// Any number of readers can hold the lock at the same time.
+    Shared,
+    /// Only a single writer can hold the lock, with no readers.
+    Exclusive,
+}
+
+/// An advisory lock on the store, released when dropped.
+#[derive(Debug)]
+pub struct StoreLock {
+    file: File,
+    /// Path to the store, set if the store has to be made immutable again when the lock is released.
+    harden: Option<PathBuf>,
+}
+
+impl Drop for StoreLock {
+    fn drop(&mut self) {
+        if let Some(store) = &self.harden {
+            let _ = set_immutable(store, true);
+        }
+
+        let _ = flock(&self.file, FlockOperation::Unlock);
+    }
+}
+
+impl crate::PackageManager {
+    /// Lock the store, blocking until the lock is available.
+    /// If a sender is given, it is notified when the lock is held by another process.
+    /// Taking the lock exclusively also recovers any interrupted transaction left in the journal.
+    pub fn lock_store(&self, mode: LockMode, tx: Option<&Sender<Event>>) -> Result<StoreLock, PackageManagerError> {
+        let lock = self.lock_store_inner(mode, tx)?;
+
+        if mode == LockMode::Exclusive {
+            for transaction in self.recover_journal()? {
+                if let Some(tx) = tx {
+                    let _ = tx.send(Event::RecoveredTransaction(transaction));
+                }
+            }
+        }
+
+        Ok(lock)
+    }
+
+    /// Recover every interrupted transaction left in the journal, returning them.
+    pub fn repair(&self) -> Result<Vec<Transaction>, PackageManagerError> {
+        let _lock = self.lock_store_inner(LockMode::Exclusive, None)?;
+
+        self.recover_journal()
+    }
+
+    fn lock_store_inner(&self, mode: LockMode, tx: Option<&Sender<Event>>) -> Result<StoreLock, PackageManagerError> {
+        let path = self.store().join(LOCK_FILE);
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)
+            .context("lock_store: open the store's lock file")?;
+
+        let (non_blocking, blocking) = match mode {
+            LockMode::Shared => (FlockOperation::NonBlockingLockShared, FlockOperation::LockShared),
+            LockMode::Exclusive => (FlockOperation::NonBlockingLockExclusive, FlockOperation::LockExclusive),
+        };
+
+        match flock(&file, non_blocking) {
+            Ok(()) => {}
+            Err(Errno::WOULDBLOCK) => {
+                if let Some(tx) = tx {
+                    let _ = tx.send(Event::AwaitingUnlock(self.store_lock_holder()));
+                }
+
+                flock(&file, blocking).context("lock_store: wait for the store's lock")?;
+
+                if let Some(tx) = tx {
+                    let _ = tx.send(Event::Unlocked);
+                }
+            }
+            Err(err) => return Err(PackageManagerError::rustix_io("lock_store: lock the store", err)),
+        }
+
+        let mut lock = StoreLock { file, harden: None };
+
+        if mode == LockMode::Exclusive {
+            lock.file.set_len(0).context("lock_store: clear the previous lock holder")?;
+            write!(lock.file, "{}", process::id()).context("lock_store: record the lock holder")?;
+
+            if self.store_config()?.immutable {
+                set_immutable(self.store(), false)?;
+                lock.harden = Some(self.store());
+            }
+        }
+
+        Ok(lock)
+    }
+
+    /// The PID of the last process that held the store's exclusive lock.
+    pub fn store_lock_holder(&self) -> Option<u32> {
+        fs::read_to_string(self.store().join(LOCK_FILE)).ok()?.trim().parse().ok()
+    }
+
+    /// Read the store settings, the default settings leave the store mutable.
+    pub fn store_config(&self) -> Result<StoreConfig, PackageManagerError> {
+        let path = self.config().join("system/store.tl");
+
+        if !path.exists() {
+            return Ok(StoreConfig::default());
+        }
+
+        eval_config(path)
+    }
+
+    pub fn store_set_immutable(&self, immutable: bool) -> Result<(), PackageManagerError> {
+        set_immutable(self.store(), immutable)
+    }
+
+    pub fn store_is_immutable(&self) -> Result<bool, PackageManagerError> {
+        let store_fd = OpenOptions::new()
+            .read(true)
+            .write(false)
+            .open(self.store())
+            .context("store_is_immutable: get a fd to the store with read-only perms")?;
+
+        let flags = ioctl_getflags(store_fd).context("store_is_immutable: get the store's flags")?;
+
+        Ok(flags.contains(IFlags::IMMUTABLE))
+    }
+}
+
+fn set_immutable(store: impl AsRef<Path>, immutable: bool) -> Result<(), PackageManagerError> {
+    let store_fd = OpenOptions::new()
+        .read(true)
+        .write(false)
+        .open(store)
+        .context("store_set_immutable: get a fd to the store with read-only perms")?;
+
+    let flags = if immutable { IFlags::IMMUTABLE } else { IFlags::empty() };
+
+    ioctl_setflags(store_fd, flags).context(format!("store_set_immutable: make the store {}", if immutable { "immutable" } else { "mutable" }))?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::test::root;
+    use std::{sync::mpsc, thread};
+
+    #[test]
+    fn shared_locks_can_be_held_together() {
+        let (_root, pm) = root(None);
+
+        let _first = pm.lock_store(LockMode::Shared, None).unwrap();
+        let _second = pm.lock_store(LockMode::Shared, None).unwrap();
+    }
+
+    #[test]
+    fn writers_wait_for_the_holder_and_record_themselves() {
+        let (_root, pm) = root(None);
+        let lock = pm.lock_store(LockMode::Exclusive, None).unwrap();
+        assert_eq!(pm.store_lock_holder(), Some(process::id()));
+
+        let (tx, rx) = mpsc::channel();
+
+        thread::scope(|scope| {
+            let writer = scope.spawn(|| pm.lock_store(LockMode::Exclusive, Some(&tx)).is_ok());
+
+            assert!(matches!(rx.recv().unwrap(), Event::AwaitingUnlock(Some(pid)) if pid == process::id()));
+            drop(lock);
+            assert!(matches!(rx.recv().unwrap(), Event::Unlocked));
+
+            assert!(writer.join().unwrap());
+        });
+    }
+}
@@ -0,0 +1,102 @@
+use crate::error::{Context, PackageManagerError};
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+use std::{
+    fs::{self, File},
+    io::{Read, Write},
+    path::{Path, PathBuf},
+};
+
+/// Name of the compressed build log inside a store item.
+pub(crate) const BUILD_LOG_FILE: &str = "build.log";
+
+/// A gzip compressed log of every build stage of a package.
+/// Logs are kept at `system/logs/<id>/<name>.log` so that failed builds which were rolled back keep theirs.
+/// Store items are built with the version as the name, other builds use their own name so they never replace an item's log.
+pub(crate) struct BuildLog {
+    path: PathBuf,
+    encoder: GzEncoder<File>,
+}
+
+impl BuildLog {
+    pub fn line(&mut self, line: &str) {
+        // A log that can't be written shouldn't fail the build.
+        let _ = writeln!(self.encoder, "{line}");
+    }
+
+    /// Finish the log and copy it into the given store item.
+    pub fn finish(self, item: Option<&Path>) -> Result<(), PackageManagerError> {
+        self.encoder.finish().context("build_log: finish writing the build log")?;
+
+        if let Some(item) = item {
+            fs::copy(&self.path, item.join(BUILD_LOG_FILE)).context("build_log: copy the build log into the store item")?;
+        }
+
+        Ok(())
+    }
+}
+
+impl crate::PackageManager {
+    pub(crate) fn create_build_log(&self, id: &str, name: &str) -> Result<BuildLog, PackageManagerError> {
+        let dir = self.logs().join(id);
+        fs::create_dir_all(&dir).context("create_build_log: create the package's log directory")?;
+
+        let path = dir.join(format!("{name}.log"));
+        let file = File::create(&path).context("create_build_log: create the build log")?;
+
+        Ok(BuildLog {
+            path,
+            encoder: GzEncoder::new(file, Compression::default()),
+        })
+    }
+
+    /// Read the log of the last build of a package, whether it succeeded or not.
+    pub fn build_log(&self, id: impl AsRef<str>) -> Result<String, PackageManagerError> {
+        let dir = self.logs().join(id.as_ref());
+
+        if !dir.exists() {
+            return Err(PackageManagerError::BuildLogNotFound);
+        }
+
+        let latest = fs::read_dir(&dir)
+            .context("build_log: list the package's build logs")?
+            .filter_map(Result::ok)
+            .filter_map(|entry| Some((entry.metadata().ok()?.modified().ok()?, entry.path())))
+            .max_by_key(|(modified, _)| *modified)
+            .map(|(_, path)| path)
+            .ok_or(PackageManagerError::BuildLogNotFound)?;
+
+        let mut log = String::new();
+        GzDecoder::new(File::open(latest).context("build_log: open the build log")?)
+            .read_to_string(&mut log)
+            .context("build_log: decompress the build log")?;
+
+        Ok(log)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{util::test::TempDir, PackageManager};
+
+    #[test]
+    fn build_logs_are_kept_and_copied_into_items() {
+        let root = TempDir::new();
+        let pm = PackageManager::new_with_root(&*root);
+        let item = root.join("store/foo-1.0.0");
+        fs::create_dir_all(&item).unwrap();
+
+        assert!(matches!(pm.build_log("foo"), Err(PackageManagerError::BuildLogNotFound)));
+
+        let mut log = pm.create_build_log("foo", "1.0.0").unwrap();
+        log.line("==> Running the build and install stages");
+        log.line("done");
+        log.finish(Some(&item)).unwrap();
+
+        assert_eq!(pm.build_log("foo").unwrap(), "==> Running the build and install stages\ndone\n");
+
+        let mut copied = String::new();
+        GzDecoder::new(File::open(item.join(BUILD_LOG_FILE)).unwrap()).read_to_string(&mut copied).unwrap();
+        assert_eq!(copied, "==> Running the build and install stages\ndone\n");
+    }
+}
@@ -1,20 +1,41 @@
-use crate::error::{Context, PackageManagerError};
-use nix::unistd::Uid;
-use rustix::fs::{IFlags, ioctl_getflags, ioctl_setflags};
+use crate::{
+    err,
+    error::{Context, PackageManagerError},
+    package::{Dependency, Package},
+    version::Version,
+};
+use serde::{Deserialize, Serialize};
 use std::{
-    fs::{self, OpenOptions},
-    path::PathBuf,
-    time::Duration,
+    collections::HashSet,
+    fs, io,
+    path::{Path, PathBuf},
 };
 
+mod archive;
+mod build;
+mod check;
 mod gc;
 mod install;
+mod journal;
+mod lock;
+mod log;
 mod remove;
+mod substitute;
+
+pub use archive::ArchiveHeader;
+pub use build::BuildOptions;
+pub use gc::GcReport;
+pub use journal::Transaction;
+pub use lock::{LockMode, StoreConfig, StoreLock};
+pub use remove::RemovePolicy;
+pub use substitute::CacheConfig;
 
-/// The interval for checking if the store is locked.
-const LOCK_POLL_INTERVAL: Duration = Duration::from_secs(1);
-/// The user id to be used in the sandbox.
-const SANDBOX_UID: Uid = Uid::from_raw(1000);
+/// Name of the file inside a store item that holds the serialized package it was built from.
+const METADATA_FILE: &str = "package.json";
+/// Version of the format of [`METADATA_FILE`], bumped whenever older metadata can't be read as the current format.
+const METADATA_FORMAT: u32 = 1;
+/// Files inside a store item that are kept by the package manager rather than produced by the build.
+const BOOKKEEPING_FILES: &[&str] = &["links", METADATA_FILE, log::BUILD_LOG_FILE];
 
 macro_rules! send {
     ($tx:expr, $event:ident) => {
@@ -35,62 +56,253 @@ macro_rules! check_err {
 
 pub(crate) use {check_err, send};
 
+/// The contents of [`METADATA_FILE`].
+/// It is JSON so that fields added to [`Package`] with a default can still be read from older items.
+#[derive(Serialize, Deserialize)]
+struct Metadata<P> {
+    format: u32,
+    package: P,
+}
+
+/// Just the format version of [`Metadata`], read before the rest so that newer formats are reported as such.
+#[derive(Deserialize)]
+struct MetadataFormat {
+    format: u32,
+}
+
 #[derive(Debug)]
 pub struct StoreItem {
     pub id: String,
     pub version: String,
     pub links: Vec<PathBuf>,
+    /// Path to the item in the store.
+    pub path: PathBuf,
+    /// The package this item was built from, if its metadata could be read.
+    pub package: Option<Package>,
+}
+
+impl StoreItem {
+    /// Name of the item's directory in the store.
+    pub fn name(&self) -> String {
+        self.path.file_name().map(|name| name.display().to_string()).unwrap_or_default()
+    }
+
+    /// An item is installed as long as it still tracks its links, removing a package deletes the links file.
+    pub fn is_installed(&self) -> bool {
+        self.path.join("links").exists()
+    }
+
+    /// Check if this item satisfies the given package id and optional version.
+    pub fn matches(&self, id: &str, version: Option<&str>) -> bool {
+        self.id == id && version.is_none_or(|version| self.version == version)
+    }
+
+    /// Check if this item declares the given item as one of its runtime dependencies.
+    pub fn depends_on(&self, other: &StoreItem) -> bool {
+        self.package
+            .as_ref()
+            .is_some_and(|package| package.runtime_deps.iter().any(|dep| other.matches(&dep.id, dep.version.as_deref())))
+    }
 }
 
 impl super::PackageManager {
-    pub fn store_set_immutable(&self, immutable: bool) -> Result<(), PackageManagerError> {
-        let store_fd = OpenOptions::new()
-            .read(true)
-            .write(false)
-            .open(self.store())
-            .context("store_set_immutable: get a fd to the store with read-only perms")?;
+    /// Check if a given store item exists.
+    pub fn get_store_item<S: AsRef<str>>(&self, id: S, version: S) -> Result<Option<StoreItem>, PackageManagerError> {
+        let item_path = self.store().join(format!("{}-{}", id.as_ref(), version.as_ref()));
 
-        let flags = if immutable { IFlags::IMMUTABLE } else { IFlags::empty() };
+        if !item_path.exists() {
+            return Ok(None);
+        }
 
-        ioctl_setflags(store_fd, flags).context(format!("store_set_immutable: make the store {}", if immutable { "immutable" } else { "mutable" }))?;
+        self.read_store_item(item_path).map(Some)
+    }
+
+    /// List every item in the store, installed or not.
+    pub fn store_items(&self) -> Result<Vec<StoreItem>, PackageManagerError> {
+        let mut items = Vec::new();
+
+        for entry in fs::read_dir(self.store()).context("store_items: list the entries in the store")?.filter_map(Result::ok) {
+            if entry.file_name() == "src" || !entry.file_type().context("store_items: skip entries that aren't directories")?.is_dir() {
+                continue;
+            }
+
+            items.push(self.read_store_item(entry.path())?);
+        }
+
+        Ok(items)
+    }
+
+    /// Find every installed store item that declares one of the given items as a runtime dependency.
+    pub fn reverse_dependencies(&self, items: &[StoreItem]) -> Result<Vec<StoreItem>, PackageManagerError> {
+        Ok(self
+            .store_items()?
+            .into_iter()
+            .filter(|item| item.is_installed())
+            .filter(|item| !items.iter().any(|other| other.path == item.path))
+            .filter(|item| items.iter().any(|other| item.depends_on(other)))
+            .collect())
+    }
+
+    /// Find the installed store items satisfying the given dependencies.
+    pub(crate) fn resolve_deps(&self, deps: &[&Dependency]) -> Result<Vec<StoreItem>, PackageManagerError> {
+        let items = self.store_items()?.into_iter().filter(StoreItem::is_installed).collect::<Vec<_>>();
+
+        if let Some(missing) = deps.iter().find(|dep| !items.iter().any(|item| item.matches(&dep.id, dep.version.as_deref()))) {
+            return Err(PackageManagerError::MissingDependency(missing.to_string()));
+        }
+
+        Ok(items.into_iter().filter(|item| deps.iter().any(|dep| item.matches(&dep.id, dep.version.as_deref()))).collect())
+    }
+
+    /// Write the metadata of the package that a store item was built from.
+    pub(crate) fn write_store_metadata(&self, path: impl AsRef<Path>, package: &Package) -> Result<(), PackageManagerError> {
+        let metadata = Metadata { format: METADATA_FORMAT, package };
+        let bytes = serde_json::to_vec_pretty(&metadata).context("write_store_metadata: serialize package")?;
+        fs::write(path.as_ref().join(METADATA_FILE), bytes).context("write_store_metadata: write package metadata to store item")?;
 
         Ok(())
     }
 
-    pub fn store_is_immutable(&self) -> Result<bool, PackageManagerError> {
-        let store_fd = OpenOptions::new()
-            .read(true)
-            .write(false)
-            .open(self.store())
-            .context("store_is_immutable: get a fd to the store with read-only perms")?;
+    /// Read a store item, an item without metadata predates it and is identified by its directory name alone.
+    pub(crate) fn read_store_item(&self, path: PathBuf) -> Result<StoreItem, PackageManagerError> {
+        let package = match fs::read(path.join(METADATA_FILE)) {
+            Ok(bytes) => Some(read_metadata(&path, &bytes)?),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => None,
+            Err(err) => return Err(PackageManagerError::io(format!("read_store_item: read the metadata of '{}'", path.display()), err)),
+        };
 
-        let flags = ioctl_getflags(store_fd).context("store_is_immutable: get the store's flags")?;
+        let (id, version) = match &package {
+            Some(package) => (package.id.clone(), package.version.clone()),
+            None => split_item_name(&path.file_name().map(|name| name.display().to_string()).unwrap_or_default()),
+        };
 
-        Ok(flags.contains(IFlags::IMMUTABLE))
+        Ok(StoreItem {
+            id,
+            version,
+            links: read_links(&path),
+            path,
+            package,
+        })
     }
+}
 
-    /// Check if a given store item exists.
-    pub fn get_store_item<S: AsRef<str>>(&self, id: S, version: S) -> Option<StoreItem> {
-        let id = id.as_ref();
-        let version = version.as_ref();
+/// Decode the metadata of the store item at `path`.
+fn read_metadata(path: &Path, bytes: &[u8]) -> Result<Package, PackageManagerError> {
+    let context = || format!("read_store_item: decode the metadata of '{}'", path.display());
+    let MetadataFormat { format } = serde_json::from_slice(bytes).context(context())?;
 
-        let item_path = self.store().join(id).join(version);
+    if format != METADATA_FORMAT {
+        return err!(UnsupportedMetadataFormat(path.to_path_buf(), format));
+    }
 
-        if !item_path.exists() {
-            return None;
+    let metadata = serde_json::from_slice::<Metadata<Package>>(bytes).context(context())?;
+
+    Ok(metadata.package)
+}
+
+/// Read the symlinks a store item tracks, an item without a links file tracks none.
+pub(crate) fn read_links(path: &Path) -> Vec<PathBuf> {
+    fs::read_to_string(path.join("links"))
+        .unwrap_or_default()
+        .lines()
+        .filter(|s| !s.trim().is_empty())
+        .map(PathBuf::from)
+        .collect()
+}
+
+/// Split the directory name of a store item into its id and version.
+/// Both can contain dashes, but a version always starts with a digit, so the first dash followed by a valid version is the separator.
+fn split_item_name(name: &str) -> (String, String) {
+    let separator = name
+        .match_indices('-')
+        .map(|(index, _)| index)
+        .find(|index| Version::parse(&name[index + 1..]).is_some())
+        .or_else(|| name.rfind('-'));
+
+    match separator {
+        Some(index) => (name[..index].to_owned(), name[index + 1..].to_owned()),
+        None => (name.to_owned(), String::new()),
+    }
+}
+
+/// Collect the names of the given roots and every item reachable from them through runtime dependencies.
+pub(crate) fn runtime_closure<'a>(items: &'a [StoreItem], roots: impl IntoIterator<Item = &'a StoreItem>) -> HashSet<String> {
+    let mut reachable = HashSet::new();
+    let mut queue = roots.into_iter().collect::<Vec<_>>();
+
+    while let Some(item) = queue.pop() {
+        if !reachable.insert(item.name()) {
+            continue;
         }
 
-        let links = fs::read_to_string(item_path.join("links"))
-            .ok()?
-            .lines()
-            .filter(|s| !s.trim().is_empty())
-            .map(PathBuf::from)
-            .collect::<Vec<_>>();
-
-        Some(StoreItem {
-            id: id.to_owned(),
-            version: version.to_owned(),
-            links,
-        })
+        let Some(package) = &item.package else {
+            continue;
+        };
+
+        for dep in &package.runtime_deps {
+            queue.extend(items.iter().filter(|other| other.matches(&dep.id, dep.version.as_deref())));
+        }
+    }
+
+    reachable
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        util::test::{add_item, package, TempDir},
+        PackageManager,
+    };
+
+    #[test]
+    fn metadata_round_trips() {
+        let root = TempDir::new();
+        let pm = PackageManager::new_with_root(&*root);
+        add_item(&pm, &package("foo-bar", "1.2.0-rc.1", &["baz@2.0.0"]), true);
+
+        let item = pm.get_store_item("foo-bar", "1.2.0-rc.1").unwrap().unwrap();
+
+        assert_eq!((item.id.as_str(), item.version.as_str()), ("foo-bar", "1.2.0-rc.1"));
+        assert_eq!(item.package.unwrap().runtime_deps[0].to_string(), "baz@2.0.0");
+    }
+
+    #[test]
+    fn undecodable_metadata_is_an_error() {
+        let root = TempDir::new();
+        let pm = PackageManager::new_with_root(&*root);
+        let path = pm.store().join("foo-1.0.0");
+        fs::create_dir_all(&path).unwrap();
+
+        fs::write(path.join(METADATA_FILE), b"\x01\x02garbage").unwrap();
+        assert!(matches!(pm.store_items(), Err(PackageManagerError::Json { .. })));
+
+        fs::write(path.join(METADATA_FILE), r#"{ "format": 999, "package": null }"#).unwrap();
+        assert!(matches!(pm.store_items(), Err(PackageManagerError::UnsupportedMetadataFormat(_, 999))));
+    }
+
+    #[test]
+    fn items_without_metadata_are_named_by_their_directory() {
+        assert_eq!(split_item_name("foo-1.0.0"), ("foo".into(), "1.0.0".into()));
+        assert_eq!(split_item_name("foo-bar-2-1.0.0-rc.1"), ("foo-bar-2".into(), "1.0.0-rc.1".into()));
+        assert_eq!(split_item_name("foo-bar-latest"), ("foo-bar".into(), "latest".into()));
+        assert_eq!(split_item_name("foo"), ("foo".into(), String::new()));
+    }
+
+    #[test]
+    fn reverse_dependencies_are_installed_dependents() {
+        let root = TempDir::new();
+        let pm = PackageManager::new_with_root(&*root);
+        add_item(&pm, &package("lib", "1.0.0", &[]), true);
+        add_item(&pm, &package("app", "1.0.0", &["lib@1.0.0"]), true);
+        add_item(&pm, &package("tool", "1.0.0", &["lib"]), true);
+        add_item(&pm, &package("old", "1.0.0", &["lib"]), false);
+        add_item(&pm, &package("other", "1.0.0", &["lib@2.0.0"]), true);
+
+        let lib = pm.get_store_item("lib", "1.0.0").unwrap().unwrap();
+        let mut dependents = pm.reverse_dependencies(&[lib]).unwrap().into_iter().map(|item| item.name()).collect::<Vec<_>>();
+        dependents.sort();
+
+        assert_eq!(dependents, ["app-1.0.0", "tool-1.0.0"]);
     }
 }
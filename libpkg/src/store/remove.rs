@@ -2,101 +2,157 @@ use crate::{
     err,
     error::{Context, PackageManagerError},
     event::Event,
-    store::{LOCK_POLL_INTERVAL, check_err, send},
-    util::append_to_file,
+    store::{LockMode, StoreItem, Transaction, check_err, journal::ignore_missing, send},
 };
-use glob::glob;
-use std::{fs, path::PathBuf, sync::mpsc::Sender, thread};
+use std::{fs, sync::mpsc::Sender};
+
+/// What to do when other installed packages still depend on the package being removed.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum RemovePolicy {
+    /// Refuse to remove the package and report its dependents.
+    #[default]
+    Refuse,
+    /// Remove the dependents along with the package.
+    Cascade,
+    /// Remove the package regardless of its dependents.
+    Force,
+}
 
 impl crate::PackageManager {
-    /// Remove the given package from the symlinks, this must be ran in a separate thread.
+    /// Remove the given package from the symlinks and the current generation, this must be ran in a separate thread.
     /// This does not remove the package from the store, to do so you need to run the garbage collector.
     /// A mpsc sender must be given to send progress events.
     /// This function requires root privileges.
-    pub fn remove<S: Into<String> + Clone>(&self, id: S, version: Option<S>, tx: &Sender<Event>) {
-        let result = self.remove_inner(id, version, tx);
-
-        check_err!(tx, self.store_set_immutable(true));
-        check_err!(tx, result);
+    pub fn remove<S: Into<String> + Clone>(&self, id: S, version: Option<S>, policy: RemovePolicy, tx: &Sender<Event>) {
+        check_err!(tx, self.remove_inner(id, version, policy, tx));
     }
 
-    fn remove_inner<S: Into<String> + Clone>(&self, id: S, version: Option<S>, tx: &Sender<Event>) -> Result<(), PackageManagerError> {
-        // Check if store is locked
-        if !self.store_is_immutable()? {
-            send!(tx, AwaitingUnlock);
-
-            while !self.store_is_immutable()? {
-                thread::sleep(LOCK_POLL_INTERVAL);
-            }
-
-            send!(tx, Unlocked);
-        }
-
-        self.store_set_immutable(false)?;
+    fn remove_inner<S: Into<String> + Clone>(&self, id: S, version: Option<S>, policy: RemovePolicy, tx: &Sender<Event>) -> Result<(), PackageManagerError> {
+        let _lock = self.lock_store(LockMode::Exclusive, Some(tx))?;
 
-        let mut links_to_remove: Vec<PathBuf> = Vec::new();
+        let id: String = id.into();
+        let version: Option<String> = version.map(Into::into);
 
-        let pattern = &format!(
-            "{}/{}-{}",
-            self.store().display(),
-            id.into(),
-            if let Some(version) = version.clone() { version.into() } else { "*".to_string() }
-        );
-        let packages = glob(pattern).map_err(|_| PackageManagerError::PackageNotInstalled)?.filter_map(Result::ok).collect::<Vec<_>>();
+        let mut to_remove = self
+            .store_items()?
+            .into_iter()
+            .filter(|item| item.is_installed() && item.matches(&id, version.as_deref()))
+            .collect::<Vec<_>>();
 
-        if packages.is_empty() {
+        if to_remove.is_empty() {
             return err!(PackageNotInstalled);
         }
 
-        for path in &packages {
-            if let Some(ver) = version.clone() {
-                let ver_path = path.join(ver.into());
+        match policy {
+            RemovePolicy::Force => {}
+            RemovePolicy::Refuse => {
+                let dependents = self.reverse_dependencies(&to_remove)?;
 
-                if !ver_path.join("links").exists() {
-                    return err!(PackageNotInstalled);
+                if !dependents.is_empty() {
+                    return err!(PackageRequired(dependents.iter().map(|item| format!("{}@{}", item.id, item.version)).collect()));
                 }
+            }
+            RemovePolicy::Cascade => loop {
+                let dependents = self.reverse_dependencies(&to_remove)?;
 
-                let links = fs::read_to_string(ver_path.join("links"))
-                    .unwrap_or_default()
-                    .lines()
-                    .filter(|s| !s.trim().is_empty())
-                    .map(PathBuf::from)
-                    .collect::<Vec<_>>();
-
-                links_to_remove.extend(links);
-            } else {
-                let versions = fs::read_dir(path)
-                    .context("remove: list versions")?
-                    .filter_map(Result::ok)
-                    .map(|entry| entry.file_name().display().to_string());
-
-                for version in versions {
-                    let links_path = path.join(version).join("links");
-
-                    if !links_path.exists() {
-                        continue;
-                    }
-
-                    let links = fs::read_to_string(&links_path)
-                        .unwrap_or_default()
-                        .lines()
-                        .filter(|s| !s.trim().is_empty())
-                        .map(|s| self.root.join(s))
-                        .collect::<Vec<_>>();
-
-                    fs::remove_file(links_path).context("remove: remove links file from package")?;
-
-                    links_to_remove.extend(links);
+                if dependents.is_empty() {
+                    break;
                 }
-            }
+
+                for dependent in &dependents {
+                    send!(tx, RemovingDependent(dependent.id.clone(), dependent.version.clone()));
+                }
+
+                to_remove.extend(dependents);
+            },
         }
 
-        append_to_file(self.store().join("garbage"), packages.iter().map(|path| path.display().to_string()).collect::<Vec<String>>().join("\n")).context("remove: append to garbage list in store")?;
+        self.journaled(Transaction::Remove { items: to_remove.iter().map(StoreItem::name).collect() }, || {
+            for item in &to_remove {
+                self.unlink_store_item(item)?;
+                self.remove_from_current_generation(item.name())?;
+            }
+
+            Ok(())
+        })
+    }
 
-        for link in &links_to_remove {
-            fs::remove_file(link).context("remove: remove all the symlinks")?;
+    /// Remove the symlinks pointing into a store item, and the file tracking them.
+    pub(crate) fn unlink_store_item(&self, item: &StoreItem) -> Result<(), PackageManagerError> {
+        for link in &item.links {
+            ignore_missing(fs::remove_file(self.root.join(link))).context("remove: remove all the symlinks")?;
         }
 
+        ignore_missing(fs::remove_file(item.path.join("links"))).context("remove: remove links file from package")?;
+
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        util::test::{add_item, package, root, TempDir},
+        PackageManager,
+    };
+    use std::sync::mpsc;
+
+    /// A root where `bar` depends on `foo` and `baz` depends on `bar`, all installed.
+    fn chain() -> (TempDir, PackageManager) {
+        let (root, pm) = root(Some(&["foo-1.0.0", "bar-1.0.0", "baz-1.0.0"]));
+        add_item(&pm, &package("foo", "1.0.0", &[]), true);
+        add_item(&pm, &package("bar", "1.0.0", &["foo"]), true);
+        add_item(&pm, &package("baz", "1.0.0", &["bar"]), true);
+
+        (root, pm)
+    }
+
+    fn remove(pm: &PackageManager, policy: RemovePolicy) -> Vec<Event> {
+        let (tx, rx) = mpsc::channel();
+        pm.remove("foo", None, policy, &tx);
+        drop(tx);
+
+        rx.iter().collect()
+    }
+
+    fn installed(pm: &PackageManager) -> Vec<String> {
+        let mut items = pm.store_items().unwrap().into_iter().filter(StoreItem::is_installed).map(|item| item.name()).collect::<Vec<_>>();
+        items.sort();
+
+        items
+    }
+
+    #[test]
+    fn refuse_reports_the_dependents() {
+        let (_root, pm) = chain();
+
+        let events = remove(&pm, RemovePolicy::Refuse);
+
+        assert!(matches!(events.as_slice(), [Event::Error(PackageManagerError::PackageRequired(dependents))] if dependents == &["bar@1.0.0"]));
+        assert_eq!(installed(&pm), ["bar-1.0.0", "baz-1.0.0", "foo-1.0.0"]);
+    }
+
+    #[test]
+    fn cascade_removes_the_dependents() {
+        let (_root, pm) = chain();
+
+        let events = remove(&pm, RemovePolicy::Cascade);
+
+        assert!(!events.iter().any(|event| matches!(event, Event::Error(_))));
+        assert_eq!(events.iter().filter(|event| matches!(event, Event::RemovingDependent(..))).count(), 2);
+        assert!(installed(&pm).is_empty());
+        assert!(pm.current_generation().unwrap().packages.is_empty());
+    }
+
+    #[test]
+    fn force_leaves_the_dependents() {
+        let (_root, pm) = chain();
+
+        let events = remove(&pm, RemovePolicy::Force);
+
+        assert!(!events.iter().any(|event| matches!(event, Event::Error(_))));
+        assert_eq!(installed(&pm), ["bar-1.0.0", "baz-1.0.0"]);
+        assert_eq!(pm.current_generation().unwrap().packages, ["bar-1.0.0", "baz-1.0.0"]);
+    }
+}
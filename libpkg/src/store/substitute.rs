@@ -0,0 +1,279 @@
+use crate::{
+    config::eval_config,
+    error::{Context, PackageManagerError},
+    event::Event,
+    hash::{hash_bytes, hash_tree},
+    package::Package,
+    store::{journal::ignore_missing, send},
+};
+use serde::Deserialize;
+use std::{fs, path::Path, sync::mpsc::Sender};
+
+/// Binary cache settings, read from `config/system/caches.tl`.
+#[derive(Debug, Default, Deserialize)]
+pub struct CacheConfig {
+    /// Base URLs of the binary caches, queried in order.
+    /// A cache serves every archive as `<hex>.pkg` next to a `<hex>.narinfo` holding the expected output hash, named by the hex of the input hash.
+    /// `file://` caches are always supported, `http://` and `https://` caches need the `repositories` feature.
+    #[serde(default)]
+    pub caches: Vec<String>,
+}
+
+/// Name of the file with the given extension that binary caches serve for the given input hash.
+fn cache_name(input_hash: &str, extension: &str) -> String {
+    format!("{}.{extension}", input_hash.split_once(':').map_or(input_hash, |(_, hex)| hex))
+}
+
+impl crate::PackageManager {
+    /// Read the binary cache settings, the default settings have no caches.
+    pub fn cache_config(&self) -> Result<CacheConfig, PackageManagerError> {
+        let path = self.config().join("system/caches.tl");
+
+        if !path.exists() {
+            return Ok(CacheConfig::default());
+        }
+
+        eval_config(path)
+    }
+
+    /// Hash everything that goes into building a package: its definition, its source and its dependencies.
+    /// Binary caches serve prebuilt archives under this hash.
+    pub fn input_hash(&self, package: &Package) -> Result<String, PackageManagerError> {
+        self.input_hash_with_src(package, &self.resolve_src(package)?)
+    }
+
+    /// Hash the inputs of a package whose source is at `src`, such as the copy kept in the store.
+    pub(crate) fn input_hash_with_src(&self, package: &Package, src: &Path) -> Result<String, PackageManagerError> {
+        let mut input = bincode::serialize(package).context("input_hash: serialize package")?;
+
+        input.extend(hash_tree(src).context("input_hash: hash the package's source")?.bytes());
+
+        let mut deps = self
+            .resolve_deps(&package.build_deps.iter().chain(&package.runtime_deps).collect::<Vec<_>>())?
+            .iter()
+            .map(|dep| dep.name())
+            .collect::<Vec<_>>();
+        deps.sort();
+
+        for dep in deps {
+            input.extend(dep.bytes());
+            input.push(0);
+        }
+
+        Ok(hash_bytes(input))
+    }
+
+    /// Try to install a package from the configured binary caches instead of building it, the store must be locked exclusively.
+    /// Returns whether the package was substituted, any failure falls back to building from source.
+    pub(crate) fn substitute(&self, package: &Package, tx: &Sender<Event>) -> Result<bool, PackageManagerError> {
+        let caches = self.cache_config()?.caches;
+
+        if caches.is_empty() {
+            return Ok(false);
+        }
+
+        let hash = self.input_hash(package)?;
+        let (name, narinfo_name) = (cache_name(&hash, "pkg"), cache_name(&hash, "narinfo"));
+
+        fs::create_dir_all(self.scratch()).context("substitute: create the scratch directory")?;
+        let (archive, narinfo) = (self.scratch().join(&name), self.scratch().join(&narinfo_name));
+
+        for cache in &caches {
+            // The outputs are checked against the hash the cache publishes for the inputs, not the one the archive carries.
+            let found = self
+                .download_from_cache(cache, &narinfo_name, &narinfo)
+                .and_then(|found| if found { self.download_from_cache(cache, &name, &archive) } else { Ok(false) });
+
+            let result = match found {
+                Ok(false) => continue,
+                Ok(true) => {
+                    send!(tx, Substituting(cache.clone()));
+                    fs::read_to_string(&narinfo)
+                        .context("substitute: read the expected output hash")
+                        .and_then(|output_hash| self.unpack_archive(&archive, output_hash.trim(), Some((package, &hash)), tx))
+                }
+                Err(err) => Err(err),
+            };
+
+            ignore_missing(fs::remove_file(&narinfo)).context("substitute: remove the downloaded narinfo")?;
+            ignore_missing(fs::remove_file(&archive)).context("substitute: remove the downloaded archive")?;
+
+            match result {
+                Ok(()) => return Ok(true),
+                Err(err) => send!(tx, SubstitutionFailed(cache.clone(), err.to_string())),
+            }
+        }
+
+        Ok(false)
+    }
+
+    /// Download a file from a binary cache, returning whether the cache had it.
+    fn download_from_cache(&self, cache: &str, name: &str, dest: &Path) -> Result<bool, PackageManagerError> {
+        if let Some(dir) = cache.strip_prefix("file://") {
+            let path = Path::new(dir).join(name);
+
+            if !path.exists() {
+                return Ok(false);
+            }
+
+            fs::copy(path, dest).context("download_from_cache: copy the file from the cache")?;
+
+            return Ok(true);
+        }
+
+        #[cfg(feature = "repositories")]
+        {
+            let url = format!("{}/{name}", cache.trim_end_matches('/'));
+            let response = reqwest::blocking::get(&url).context("download_from_cache: request the file")?;
+
+            if response.status() == reqwest::StatusCode::NOT_FOUND {
+                return Ok(false);
+            }
+
+            let bytes = response
+                .error_for_status()
+                .context("download_from_cache: request the file")?
+                .bytes()
+                .context("download_from_cache: download the file")?;
+            fs::write(dest, bytes).context("download_from_cache: write the downloaded file")?;
+
+            Ok(true)
+        }
+
+        #[cfg(not(feature = "repositories"))]
+        Err(PackageManagerError::UnsupportedCache(cache.to_owned()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::cache_name;
+    use crate::{
+        event::Event,
+        package::{Package, Src},
+        util::test::{add_item, package, root, TempDir},
+        PackageManager,
+    };
+    use std::{fs, path::Path, sync::mpsc};
+
+    fn foo(src: &Path, version: &str) -> Package {
+        let mut foo = package("foo", version, &[]);
+        foo.src = Src::Path(src.to_path_buf());
+        foo
+    }
+
+    /// A root that substitutes from a single file:// cache.
+    fn consumer(cache: &Path) -> (TempDir, PackageManager) {
+        let (root, pm) = root(Some(&[]));
+        fs::create_dir_all(pm.config().join("system")).unwrap();
+        fs::write(pm.config().join("system/caches.tl"), format!("{{\n    caches = [ \"file://{}\" ]\n}}\n", cache.display())).unwrap();
+
+        (root, pm)
+    }
+
+    /// Substitute a package, returning whether it was substituted and the caches that failed.
+    fn substitute(pm: &PackageManager, package: &Package) -> (bool, Vec<String>) {
+        let (tx, rx) = mpsc::channel();
+        let substituted = pm.substitute(package, &tx).unwrap();
+        drop(tx);
+
+        let failed = rx
+            .into_iter()
+            .filter_map(|event| match event {
+                Event::SubstitutionFailed(cache, _) => Some(cache),
+                _ => None,
+            })
+            .collect();
+
+        (substituted, failed)
+    }
+
+    /// Bundle foo 1.0.0 from a builder root into a cache, returning the cache and the archive's input hash.
+    fn cache_with_foo(src: &Path) -> (TempDir, String) {
+        let (_builder_root, builder) = root(Some(&["foo-1.0.0"]));
+        add_item(&builder, &foo(src, "1.0.0"), true);
+        fs::create_dir_all(builder.store().join("foo-1.0.0/bin")).unwrap();
+        fs::write(builder.store().join("foo-1.0.0/bin/foo"), "foo").unwrap();
+
+        let cache = TempDir::new();
+        let bundled = builder.store().join("foo.pkg");
+        let header = builder.bundle("foo", None, &bundled).unwrap();
+        fs::copy(&bundled, cache.join(cache_name(&header.input_hash, "pkg"))).unwrap();
+        fs::write(cache.join(cache_name(&header.input_hash, "narinfo")), format!("{}\n", header.hash)).unwrap();
+
+        (cache, header.input_hash)
+    }
+
+    #[test]
+    fn cached_archives_are_substituted() {
+        let src = TempDir::new();
+        fs::write(src.join("main.c"), "int main() {}").unwrap();
+        let (cache, input_hash) = cache_with_foo(&src);
+        let (_root, pm) = consumer(&cache);
+
+        assert_eq!(pm.input_hash(&foo(&src, "1.0.0")).unwrap(), input_hash);
+        assert_eq!(substitute(&pm, &foo(&src, "1.0.0")), (true, vec![]));
+        assert_eq!(fs::read_to_string(pm.store().join("foo-1.0.0/bin/foo")).unwrap(), "foo");
+    }
+
+    #[test]
+    fn missing_archives_fall_back_to_building() {
+        let src = TempDir::new();
+        let (cache, _) = cache_with_foo(&src);
+        let (_root, pm) = consumer(&cache);
+
+        assert_eq!(substitute(&pm, &foo(&src, "2.0.0")), (false, vec![]));
+        assert!(!pm.store().join("foo-2.0.0").exists());
+    }
+
+    #[test]
+    fn corrupted_archives_are_rejected() {
+        let src = TempDir::new();
+        let (cache, input_hash) = cache_with_foo(&src);
+        let (_root, pm) = consumer(&cache);
+        fs::write(cache.join(cache_name(&input_hash, "pkg")), "not an archive").unwrap();
+
+        assert_eq!(substitute(&pm, &foo(&src, "1.0.0")), (false, vec![format!("file://{}", cache.display())]));
+        assert!(!pm.store().join("foo-1.0.0").exists());
+    }
+
+    #[test]
+    fn archives_built_from_other_inputs_are_rejected() {
+        let src = TempDir::new();
+        let (cache, input_hash) = cache_with_foo(&src);
+        let (_root, pm) = consumer(&cache);
+
+        // The source changed since the archive was built, so serving it under the new hash must not install it.
+        fs::write(src.join("main.c"), "int main() { return 1; }").unwrap();
+        let changed = pm.input_hash(&foo(&src, "1.0.0")).unwrap();
+        assert_ne!(changed, input_hash);
+        for extension in ["pkg", "narinfo"] {
+            fs::rename(cache.join(cache_name(&input_hash, extension)), cache.join(cache_name(&changed, extension))).unwrap();
+        }
+
+        assert_eq!(substitute(&pm, &foo(&src, "1.0.0")), (false, vec![format!("file://{}", cache.display())]));
+        assert!(!pm.store().join("foo-1.0.0").exists());
+    }
+
+    #[test]
+    fn archives_without_a_published_hash_are_not_substituted() {
+        let src = TempDir::new();
+        let (cache, input_hash) = cache_with_foo(&src);
+        let (_root, pm) = consumer(&cache);
+        fs::remove_file(cache.join(cache_name(&input_hash, "narinfo"))).unwrap();
+
+        assert_eq!(substitute(&pm, &foo(&src, "1.0.0")), (false, vec![]));
+        assert!(!pm.store().join("foo-1.0.0").exists());
+    }
+
+    #[test]
+    fn archives_that_dont_match_the_published_hash_are_rejected() {
+        let src = TempDir::new();
+        let (cache, input_hash) = cache_with_foo(&src);
+        let (_root, pm) = consumer(&cache);
+        fs::write(cache.join(cache_name(&input_hash, "narinfo")), "sha256:0000\n").unwrap();
+
+        assert_eq!(substitute(&pm, &foo(&src, "1.0.0")), (false, vec![format!("file://{}", cache.display())]));
+        assert!(!pm.store().join("foo-1.0.0").exists());
+    }
+}
@@ -0,0 +1,259 @@
+use serde::{
+    de::{MapAccess, SeqAccess, Visitor},
+    Deserialize, Deserializer,
+};
+use std::{
+    fmt::{self, Write},
+    sync::{Arc, Mutex},
+};
+use tl::runtime::types::Value;
+
+/// A step in the evaluation of a package file, collected by [`Package::eval_traced`](crate::package::Package::eval_traced).
+#[derive(Debug, Clone)]
+pub enum TraceEvent {
+    /// A native function was called, with its arguments printed as TL.
+    Call { function: String, args: Vec<String> },
+    /// The file evaluated to a value, pretty-printed as TL.
+    Result(String),
+}
+
+/// Collects trace events from the native functions of a scope, does nothing when tracing is off.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Tracer(Option<Arc<Mutex<Vec<TraceEvent>>>>);
+
+impl Tracer {
+    pub fn new(enabled: bool) -> Self {
+        Self(enabled.then(Arc::default))
+    }
+
+    pub fn call<'a>(&self, function: &str, args: impl IntoIterator<Item = &'a Value>) {
+        self.push(|| TraceEvent::Call {
+            function: function.to_owned(),
+            args: args.into_iter().map(print).collect(),
+        });
+    }
+
+    pub fn result(&self, value: &Value) {
+        self.push(|| TraceEvent::Result(print(value)));
+    }
+
+    /// Take the collected events.
+    pub fn finish(self) -> Vec<TraceEvent> {
+        self.0.map(|events| events.lock().map(|events| events.clone()).unwrap_or_default()).unwrap_or_default()
+    }
+
+    fn push(&self, event: impl FnOnce() -> TraceEvent) {
+        if let Some(events) = &self.0
+            && let Ok(mut events) = events.lock()
+        {
+            events.push(event());
+        }
+    }
+}
+
+/// Pretty-print a value as TL.
+pub(crate) fn print(value: &Value) -> String {
+    let mut printed = String::new();
+
+    match Printed::deserialize(value.clone()) {
+        Ok(value) => value.write(&mut printed, 0),
+        Err(err) => printed = format!("<{err}>"),
+    }
+
+    printed
+}
+
+/// A TL value in a form that can be printed, read through the value's `Deserializer` implementation.
+enum Printed {
+    Null,
+    Bool(bool),
+    Int(i64),
+    UInt(u64),
+    Float(f64),
+    String(String),
+    List(Vec<Printed>),
+    Object(Vec<(String, Printed)>),
+}
+
+impl Printed {
+    fn write(&self, out: &mut String, depth: usize) {
+        let indent = "    ".repeat(depth + 1);
+        let closing = "    ".repeat(depth);
+
+        let _ = match self {
+            Self::Null => write!(out, "null"),
+            Self::Bool(value) => write!(out, "{value}"),
+            Self::Int(value) => write!(out, "{value}"),
+            Self::UInt(value) => write!(out, "{value}"),
+            Self::Float(value) => write!(out, "{value}"),
+            Self::String(value) => write!(out, "{value:?}"),
+            Self::List(items) if items.is_empty() => write!(out, "[ ]"),
+            Self::Object(fields) if fields.is_empty() => write!(out, "{{ }}"),
+            Self::List(items) => {
+                out.push_str("[\n");
+
+                for item in items {
+                    out.push_str(&indent);
+                    item.write(out, depth + 1);
+                    out.push('\n');
+                }
+
+                write!(out, "{closing}]")
+            }
+            Self::Object(fields) => {
+                out.push_str("{\n");
+
+                for (key, value) in fields {
+                    let _ = write!(out, "{indent}{key} = ");
+                    value.write(out, depth + 1);
+                    out.push('\n');
+                }
+
+                write!(out, "{closing}}}")
+            }
+        };
+    }
+}
+
+impl<'de> Deserialize<'de> for Printed {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_any(PrintedVisitor)
+    }
+}
+
+struct PrintedVisitor;
+
+impl<'de> Visitor<'de> for PrintedVisitor {
+    type Value = Printed;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "any TL value")
+    }
+
+    fn visit_unit<E>(self) -> Result<Printed, E> {
+        Ok(Printed::Null)
+    }
+
+    fn visit_none<E>(self) -> Result<Printed, E> {
+        Ok(Printed::Null)
+    }
+
+    fn visit_some<D: Deserializer<'de>>(self, deserializer: D) -> Result<Printed, D::Error> {
+        Printed::deserialize(deserializer)
+    }
+
+    fn visit_bool<E>(self, value: bool) -> Result<Printed, E> {
+        Ok(Printed::Bool(value))
+    }
+
+    fn visit_i64<E>(self, value: i64) -> Result<Printed, E> {
+        Ok(Printed::Int(value))
+    }
+
+    fn visit_u64<E>(self, value: u64) -> Result<Printed, E> {
+        Ok(Printed::UInt(value))
+    }
+
+    fn visit_f64<E>(self, value: f64) -> Result<Printed, E> {
+        Ok(Printed::Float(value))
+    }
+
+    fn visit_str<E>(self, value: &str) -> Result<Printed, E> {
+        Ok(Printed::String(value.to_owned()))
+    }
+
+    fn visit_string<E>(self, value: String) -> Result<Printed, E> {
+        Ok(Printed::String(value))
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Printed, A::Error> {
+        let mut items = Vec::new();
+
+        while let Some(item) = seq.next_element()? {
+            items.push(item);
+        }
+
+        Ok(Printed::List(items))
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Printed, A::Error> {
+        let mut fields = Vec::new();
+
+        while let Some(field) = map.next_entry()? {
+            fields.push(field);
+        }
+
+        Ok(Printed::Object(fields))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{package::Package, stdlib, util::test::TempDir};
+    use std::fs;
+    use tl::{parser::parse, runtime::Scope, Source};
+
+    const PACKAGE: &str = r#"let common = import("./common.tl")
+
+package({
+    id = "foo"
+    version = maybe(env("PKG_TRACE_UNSET") common.version)
+    description = readFile("./description.txt")
+    src = "."
+    build = ""
+    install = ""
+})
+"#;
+
+    /// Write a package file that imports a file, reads a file and an environment variable.
+    fn package_dir() -> TempDir {
+        let dir = TempDir::new();
+
+        fs::write(dir.join("package.tl"), PACKAGE).unwrap();
+        fs::write(dir.join("common.tl"), "{ version = \"1.0.0\" }\n").unwrap();
+        fs::write(dir.join("description.txt"), "A package").unwrap();
+
+        dir
+    }
+
+    /// The traced calls of a function, with their arguments.
+    fn calls<'a>(events: &'a [TraceEvent], function: &str) -> Vec<&'a [String]> {
+        events
+            .iter()
+            .filter_map(|event| match event {
+                TraceEvent::Call { function: name, args } if name == function => Some(args.as_slice()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn traced_calls_are_recorded_with_their_arguments() {
+        let dir = package_dir();
+        let (result, events) = Package::eval_traced(Source::from_path(dir.join("package.tl")).unwrap());
+
+        assert!(result.is_ok());
+        assert_eq!(calls(&events, "import"), [["\"./common.tl\""]]);
+        assert_eq!(calls(&events, "readFile"), [["\"./description.txt\""]]);
+        assert_eq!(calls(&events, "env"), [["\"PKG_TRACE_UNSET\""]]);
+        assert_eq!(calls(&events, "maybe"), [["null", "\"1.0.0\""]]);
+        assert!(matches!(events.last(), Some(TraceEvent::Result(result)) if result.contains("kind = \"Package\"")));
+    }
+
+    #[test]
+    fn untraced_evaluation_records_nothing() {
+        let dir = package_dir();
+        let source = Source::from_path(dir.join("package.tl")).unwrap();
+        let Ok(ast) = parse(&source) else {
+            panic!("the package file should parse");
+        };
+        let mut scope = Scope::new(source, ast);
+        let tracer = Tracer::default();
+
+        stdlib::register(&mut scope, &tracer, Some(dir.join("package.tl")));
+
+        assert!(scope.eval().is_ok());
+        assert!(tracer.finish().is_empty());
+    }
+}
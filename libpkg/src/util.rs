@@ -1,12 +1,121 @@
 use std::{
-    fs::OpenOptions,
-    io::{self, Write},
-    path::Path,
+    fs, io,
+    path::{Path, PathBuf},
+    process,
+    sync::atomic::{AtomicUsize, Ordering},
 };
 
-/// Append to a string list file
-pub fn append_to_file(path: impl AsRef<Path>, to_append: impl Into<String>) -> io::Result<()> {
-    let mut file = OpenOptions::new().append(true).create(true).open(path)?;
-    writeln!(file, "{}", to_append.into())?;
-    Ok(())
+/// Get the total size in bytes of a file or directory, symlinks are not followed.
+pub fn disk_usage(path: impl AsRef<Path>) -> io::Result<u64> {
+    let metadata = fs::symlink_metadata(path.as_ref())?;
+
+    if !metadata.is_dir() {
+        return Ok(metadata.len());
+    }
+
+    let mut size = 0;
+
+    for entry in fs::read_dir(path)?.flatten() {
+        size += disk_usage(entry.path())?;
+    }
+
+    Ok(size)
+}
+
+/// Create a new directory in `parent` named after `prefix`, that no other build can be using.
+pub fn create_unique_dir(parent: impl AsRef<Path>, prefix: &str) -> io::Result<PathBuf> {
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+    fs::create_dir_all(parent.as_ref())?;
+
+    loop {
+        let path = parent.as_ref().join(format!("{prefix}-{}-{}", process::id(), COUNTER.fetch_add(1, Ordering::Relaxed)));
+
+        match fs::create_dir(&path) {
+            Ok(()) => return Ok(path),
+            // Left behind by an earlier process with the same PID.
+            Err(err) if err.kind() == io::ErrorKind::AlreadyExists => continue,
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    use crate::{package::Package, PackageManager};
+    use std::{
+        env, fs,
+        ops::Deref,
+        path::{Path, PathBuf},
+        process,
+        sync::atomic::{AtomicUsize, Ordering},
+    };
+
+    /// A directory under the system's temporary directory that is removed when dropped.
+    pub struct TempDir(PathBuf);
+
+    impl TempDir {
+        pub fn new() -> Self {
+            static COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+            let path = env::temp_dir().join(format!("libpkg-test-{}-{}", process::id(), COUNTER.fetch_add(1, Ordering::Relaxed)));
+            fs::create_dir_all(&path).unwrap();
+
+            Self(path)
+        }
+    }
+
+    impl Deref for TempDir {
+        type Target = Path;
+
+        fn deref(&self) -> &Path {
+            &self.0
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    /// A minimal package with the given runtime dependencies.
+    pub fn package(id: &str, version: &str, runtime_deps: &[&str]) -> Package {
+        serde_json::from_value(serde_json::json!({
+            "id": id,
+            "version": version,
+            "runtime_deps": runtime_deps,
+            "src": ".",
+            "build": "",
+            "install": "",
+        }))
+        .unwrap()
+    }
+
+    /// Add an item built from the given package to the store, an installed item tracks its links.
+    pub fn add_item(pm: &PackageManager, package: &Package, installed: bool) {
+        let path = pm.store().join(format!("{}-{}", package.id, package.version));
+        fs::create_dir_all(&path).unwrap();
+        pm.write_store_metadata(&path, package).unwrap();
+
+        if installed {
+            fs::write(path.join("links"), "").unwrap();
+        }
+    }
+
+    /// Create the directories of a root with a current generation 1 listing `manifest`, or with no manifest at all.
+    pub fn root(manifest: Option<&[&str]>) -> (TempDir, PackageManager) {
+        let root = TempDir::new();
+        let pm = PackageManager::new_with_root(&*root);
+
+        fs::create_dir_all(pm.store().join("src")).unwrap();
+        fs::create_dir_all(pm.generations().join("1")).unwrap();
+        pm.set_current_generation(1).unwrap();
+
+        if let Some(manifest) = manifest {
+            fs::write(pm.generations().join("1/manifest"), manifest.join("\n")).unwrap();
+        }
+
+        (root, pm)
+    }
 }
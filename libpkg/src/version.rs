@@ -0,0 +1,134 @@
+use std::cmp::Ordering;
+
+/// A semantic version, `major.minor.patch` optionally followed by a `-prerelease` and a `+build`.
+/// Versions are ordered by precedence, so the build metadata is ignored when comparing them.
+#[derive(Debug, Clone)]
+pub(crate) struct Version {
+    pub major: u64,
+    pub minor: u64,
+    pub patch: u64,
+    pub pre: Vec<String>,
+}
+
+impl Version {
+    pub fn parse(version: &str) -> Option<Self> {
+        let (version, build) = version.split_once('+').map_or((version, None), |(version, build)| (version, Some(build)));
+        let (version, pre) = version.split_once('-').map_or((version, None), |(version, pre)| (version, Some(pre)));
+
+        let identifiers = |text: &str| {
+            text.split('.')
+                .all(|identifier| !identifier.is_empty() && identifier.chars().all(|c| c.is_ascii_alphanumeric() || c == '-'))
+        };
+
+        if build.is_some_and(|build| !identifiers(build)) || pre.is_some_and(|pre| !identifiers(pre)) {
+            return None;
+        }
+
+        let number = |part: &str| (part == "0" || !part.starts_with('0')).then(|| part.parse().ok()).flatten();
+        let mut parts = version.split('.');
+        let (major, minor, patch) = (number(parts.next()?)?, number(parts.next()?)?, number(parts.next()?)?);
+
+        if parts.next().is_some() {
+            return None;
+        }
+
+        Some(Self {
+            major,
+            minor,
+            patch,
+            pre: pre.map(|pre| pre.split('.').map(ToOwned::to_owned).collect()).unwrap_or_default(),
+        })
+    }
+}
+
+impl PartialEq for Version {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Version {}
+
+impl PartialOrd for Version {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Version {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (self.major, self.minor, self.patch).cmp(&(other.major, other.minor, other.patch)).then_with(|| {
+            // A prerelease comes before the release itself.
+            match (self.pre.is_empty(), other.pre.is_empty()) {
+                (true, true) => Ordering::Equal,
+                (true, false) => Ordering::Greater,
+                (false, true) => Ordering::Less,
+                (false, false) => {
+                    for (a, b) in self.pre.iter().zip(&other.pre) {
+                        // Numeric identifiers are compared as numbers and come before alphanumeric ones.
+                        let ordering = match (a.parse::<u64>(), b.parse::<u64>()) {
+                            (Ok(a), Ok(b)) => a.cmp(&b),
+                            (Ok(_), Err(_)) => Ordering::Less,
+                            (Err(_), Ok(_)) => Ordering::Greater,
+                            (Err(_), Err(_)) => a.cmp(b),
+                        };
+
+                        if ordering != Ordering::Equal {
+                            return ordering;
+                        }
+                    }
+
+                    self.pre.len().cmp(&other.pre.len())
+                }
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn version(version: &str) -> Version {
+        Version::parse(version).unwrap_or_else(|| panic!("\"{version}\" should be valid"))
+    }
+
+    #[test]
+    fn only_full_versions_are_valid() {
+        for valid in ["0.0.0", "1.2.3", "10.20.30", "1.0.0-alpha", "1.0.0-alpha.1", "1.0.0-x-y.7", "1.0.0+build.5", "1.0.0-rc.1+sha.abc"] {
+            assert!(Version::parse(valid).is_some(), "{valid}");
+        }
+
+        for invalid in ["", "1", "1.0", "1.0.0.0", "01.0.0", "1.02.0", "v1.0.0", "1.0.0-", "1.0.0+", "1.0.0-alpha..1", "1.0.0-al_pha", "-1.0.0"] {
+            assert!(Version::parse(invalid).is_none(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn versions_are_ordered_by_precedence() {
+        let ordered = [
+            "1.0.0-alpha",
+            "1.0.0-alpha.1",
+            "1.0.0-alpha.beta",
+            "1.0.0-beta",
+            "1.0.0-beta.2",
+            "1.0.0-beta.11",
+            "1.0.0-rc.1",
+            "1.0.0",
+            "1.0.1",
+            "1.2.0",
+            "1.10.0",
+            "2.0.0",
+        ];
+
+        for pair in ordered.windows(2) {
+            assert!(version(pair[0]) < version(pair[1]), "{} < {}", pair[0], pair[1]);
+        }
+    }
+
+    #[test]
+    fn build_metadata_is_ignored() {
+        assert_eq!(version("1.0.0+linux"), version("1.0.0+macos"));
+        assert_eq!(version("1.0.0-rc.1+1"), version("1.0.0-rc.1"));
+    }
+}
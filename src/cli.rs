@@ -1,4 +1,4 @@
-use prelude::clap::{self, Parser, Subcommand};
+use prelude::clap::{self, Parser, Subcommand, ValueEnum};
 use std::path::PathBuf;
 
 #[derive(Debug, Parser)]
@@ -6,6 +6,9 @@ use std::path::PathBuf;
 pub struct Cli {
     #[clap(short, long, default_value = "/")]
     pub root: PathBuf,
+    /// Trace the native function calls and the resulting value of every package file that is evaluated.
+    #[clap(long, global = true)]
+    pub trace_eval: bool,
     #[clap(subcommand)]
     pub command: Command,
 }
@@ -16,30 +19,102 @@ pub enum Command {
     Install {
         #[clap(value_parser = parse_install_source)]
         source: InstallSource,
+        /// The output hash that `pkg bundle` printed, required when installing an archive.
+        #[clap(long)]
+        hash: Option<String>,
     },
     #[clap(aliases = ["r", "rm"])]
-    Remove { id: String },
+    Remove {
+        id: String,
+        /// Also remove every package that depends on this one.
+        #[clap(long, conflicts_with = "force")]
+        cascade: bool,
+        /// Remove the package even if other packages still depend on it.
+        #[clap(long)]
+        force: bool,
+    },
     #[clap(alias = "init")]
     InitRoot,
+    /// Delete everything in the store that isn't reachable from a generation.
+    Gc {
+        /// Only report what would be deleted.
+        #[clap(long)]
+        dry_run: bool,
+    },
+    /// Recover store operations that were interrupted.
+    Repair,
+    /// Show the log of the last build of a package.
+    Log { id: String },
+    /// Build a package from its definition without installing it.
+    Build {
+        path: PathBuf,
+        /// Rebuild an installed package and compare the output with its store item.
+        #[clap(long, conflicts_with = "output")]
+        check: bool,
+        /// Where to put the build output, defaults to a temporary directory.
+        #[clap(short, long)]
+        output: Option<PathBuf>,
+        /// Leave the build's scratch directory around for debugging.
+        #[clap(long)]
+        keep_sandbox: bool,
+        /// Open an interactive shell in the sandbox of a stage that failed.
+        #[clap(long, conflicts_with = "check")]
+        shell_on_failure: bool,
+    },
+    /// Check a package file for common mistakes.
+    /// Every lint is printed as a tab-separated line of the file, severity, code, id, field and message.
+    Lint { path: PathBuf },
+    /// Create a package file for a project, filled in from its existing manifest where there is one.
+    New {
+        dir: PathBuf,
+        #[clap(short, long, value_enum)]
+        template: Template,
+    },
+    /// Show a package from a package file with the local overlays applied, or an installed package by its id.
+    Info { source: String },
+    /// Export an installed package as an archive that can be installed without building it.
+    Bundle {
+        id: String,
+        /// Where to write the archive, defaults to `<id>-<version>.pkg` in the current directory.
+        #[clap(short, long)]
+        output: Option<PathBuf>,
+    },
 }
 
 impl Command {
     pub fn needs_complete_root(&self) -> bool {
-        !matches!(self, Self::InitRoot { .. })
+        !matches!(self, Self::InitRoot { .. } | Self::Lint { .. } | Self::New { .. })
     }
 }
 
+/// The kind of project that `pkg new` creates a package file for.
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum)]
+pub enum Template {
+    /// A Cargo project, read from `Cargo.toml` when building if there is one.
+    Rust,
+    /// A C project built with Meson if there is a `meson.build`, or with Make otherwise.
+    C,
+    /// A single executable script, filled in from `package.json` if there is one.
+    Script,
+    /// A group of other packages.
+    Meta,
+}
+
 #[derive(Debug, Clone, PartialEq)]
 #[allow(dead_code)]
 pub enum InstallSource {
     Name(String),
     Path(PathBuf),
+    /// A prebuilt package archive made by `pkg bundle`.
+    Archive(PathBuf),
 }
 
 fn parse_install_source(input: &str) -> Result<InstallSource, String> {
     let path = PathBuf::from(input);
 
-    if path.exists() && path.is_file() {
+    if path.exists() && path.is_file() && path.extension().is_some_and(|ext| ext == "pkg") {
+        Ok(InstallSource::Archive(path))
+    } else if path.exists() && path.is_file() {
         Ok(InstallSource::Path(path))
     } else {
         Ok(InstallSource::Name(input.to_string()))
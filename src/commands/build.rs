@@ -0,0 +1,62 @@
+use crate::{err, error::Error};
+use libpkg::{error::PackageManagerError, event::Event, package::Definition, BuildOptions, PackageManager};
+use prelude::logger::{error, info, trace};
+use std::{path::PathBuf, sync::mpsc, thread};
+
+pub fn build(pm: PackageManager, path: PathBuf, check: bool, options: BuildOptions, trace_eval: bool) -> Result<(), Error> {
+    let Definition::Package(package) = super::eval_package(path, trace_eval)? else {
+        return err!(GroupNotBuildable);
+    };
+
+    let (tx, rx) = mpsc::channel();
+
+    if check {
+        info!("Rebuilding \"{}\" to check that it is reproducible", package.name);
+        thread::spawn(move || pm.check(package, &tx));
+    } else {
+        info!("Building \"{}\"", package.name);
+        thread::spawn(move || pm.build(package, options, &tx));
+    }
+
+    let mut differences = 0;
+
+    while let Ok(event) = rx.recv() {
+        use Event as E;
+        use PackageManagerError as PkgError;
+
+        match event {
+            E::AwaitingUnlock(Some(pid)) => info!("The package store is locked by another process (pid {pid})"),
+            E::AwaitingUnlock(None) => info!("The package store is locked because of other processes using it"),
+            E::Unlocked => info!("Package store unlocked"),
+            E::RecoveredTransaction(transaction) => info!("Recovered interrupted {transaction}"),
+            E::AllocatingInStore => trace!("Creating directory in package store"),
+            E::CopySrcProgress(_copied, _total) => {
+                // TODO: Render a progress bar
+            }
+            E::BuildLog(line) => info!("{line}"),
+            E::DebugShell => error!("The build failed, opening a shell in its sandbox. Exit the shell to clean up the build"),
+            E::ScratchKept(path) => info!("Kept the build's scratch directory at \"{}\"", path.display()),
+            E::Built(path) => info!("Build output is at \"{}\"", path.display()),
+            E::OutputDiffers(path) => {
+                error!("Differs: {}", path.display());
+                differences += 1;
+            }
+            E::Substituting(..) | E::SubstitutionFailed(..) | E::RemovingDependent(..) => {}
+
+            E::Error(err) => match err {
+                PkgError::PackageNotInstalled => return err!(CheckNotInstalled),
+                _ => return Err(err.into()),
+            },
+        }
+    }
+
+    if differences > 0 {
+        return err!(NotReproducible(differences));
+    }
+
+    if check {
+        info!("The rebuilt output is identical to the installed package");
+    }
+
+    Ok(())
+}
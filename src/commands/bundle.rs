@@ -0,0 +1,22 @@
+use libpkg::PackageManager;
+use prelude::logger::{error, info};
+use std::path::PathBuf;
+
+use crate::error::Error;
+
+pub fn bundle(pm: &PackageManager, id: impl AsRef<str>, output: Option<PathBuf>) -> Result<(), Error> {
+    let id = id.as_ref();
+
+    let Some(item) = pm.store_items()?.into_iter().find(|item| item.is_installed() && item.matches(id, None)) else {
+        error!("Package not installed");
+        return Ok(());
+    };
+
+    let path = output.unwrap_or_else(|| PathBuf::from(format!("{}.pkg", item.name())));
+    let header = pm.bundle(id, Some(&item.version), &path)?;
+
+    info!("Bundled \"{}\" into \"{}\" ({})", item.name(), path.display(), header.hash);
+    info!("Install it with `pkg install {} --hash {}`", path.display(), header.hash);
+
+    Ok(())
+}
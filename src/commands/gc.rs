@@ -0,0 +1,34 @@
+use libpkg::PackageManager;
+use prelude::logger::info;
+
+use crate::error::Error;
+
+pub fn gc(pm: &PackageManager, dry_run: bool) -> Result<(), Error> {
+    let report = pm.store_gc(dry_run)?;
+
+    for id in &report.pruned {
+        info!("{} generation {id}", if dry_run { "Would prune" } else { "Pruned" });
+    }
+
+    for path in &report.removed {
+        info!("{} \"{}\"", if dry_run { "Would remove" } else { "Removed" }, path.display());
+    }
+
+    info!("{} {}", if dry_run { "Would free" } else { "Freed" }, format_bytes(report.freed));
+
+    Ok(())
+}
+
+fn format_bytes(bytes: u64) -> String {
+    const UNITS: &[&str] = &["B", "KiB", "MiB", "GiB", "TiB"];
+
+    let mut size = bytes as f64;
+    let mut unit = 0;
+
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+
+    if unit == 0 { format!("{bytes} {}", UNITS[0]) } else { format!("{size:.1} {}", UNITS[unit]) }
+}
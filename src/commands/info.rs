@@ -0,0 +1,64 @@
+use crate::{err, error::Error};
+use libpkg::{
+    package::{Definition, Dependency, Package},
+    PackageManager,
+};
+use prelude::logger::info;
+use std::path::PathBuf;
+
+/// Show a package from a package file, with the local overlays applied, or an installed package by its id.
+pub fn info(pm: &PackageManager, source: String, trace_eval: bool) -> Result<(), Error> {
+    let path = PathBuf::from(&source);
+
+    if !path.is_file() {
+        let item = pm.store_items()?.into_iter().find(|item| item.is_installed() && item.matches(&source, None));
+
+        let Some(package) = item.and_then(|item| item.package) else {
+            return err!(InfoNotInstalled(source));
+        };
+
+        print_package(&package);
+        return Ok(());
+    }
+
+    match super::eval_package(path, trace_eval)? {
+        Definition::Package(mut package) => {
+            pm.apply_overlays(&mut package)?;
+            print_package(&package);
+        }
+        Definition::Group(group) => {
+            info!("{} ({}): {}", group.name, group.id, group.description);
+
+            let (packages, references) = group.members();
+
+            for mut package in packages {
+                pm.apply_overlays(&mut package)?;
+                print_package(&package);
+            }
+
+            if !references.is_empty() {
+                info!("References: {}", join(&references));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn print_package(package: &Package) {
+    info!("{} {} ({})", package.name, package.version, package.id);
+    info!("  Description: {}", package.description);
+    info!("  Authors: {}", if package.authors.is_empty() { "none".into() } else { package.authors.join(", ") });
+    info!("  Source: {}", package.src);
+    info!("  Build dependencies: {}", join(&package.build_deps));
+    info!("  Runtime dependencies: {}", join(&package.runtime_deps));
+    info!("  Overlays: {}", if package.overlays.is_empty() { "none".into() } else { package.overlays.join(", ") });
+}
+
+fn join(deps: &[Dependency]) -> String {
+    if deps.is_empty() {
+        return "none".into();
+    }
+
+    deps.iter().map(ToString::to_string).collect::<Vec<_>>().join(", ")
+}
@@ -1,46 +1,76 @@
-#![allow(unused_imports)]
-
-use crate::{cli::InstallSource, error::Error};
-use libpkg::{
-    error::{Context, PackageManagerError},
-    event::Event,
-    package::Package,
-    PackageManager, Source,
-};
+use crate::{cli::InstallSource, err, error::Error};
+use libpkg::{error::PackageManagerError, event::Event, package::Definition, PackageManager};
 use prelude::logger::{error, info, trace};
-use std::{sync::mpsc, thread};
+use std::{
+    path::PathBuf,
+    sync::mpsc::{self, Receiver},
+    thread,
+};
 
-pub fn install(_pm: PackageManager, source: InstallSource) -> Result<(), Error> {
-    let package = match source {
+pub fn install(pm: PackageManager, source: InstallSource, hash: Option<String>, trace_eval: bool) -> Result<(), Error> {
+    let definition = match source {
         InstallSource::Name(_) => unimplemented!("fetch packages from repositories"),
-        InstallSource::Path(path) => Package::eval(Source::from_path(path).context("pkg: read from given install path")?)?,
+        InstallSource::Archive(path) => {
+            let Some(hash) = hash else {
+                return err!(ArchiveHashRequired);
+            };
+
+            return install_archive(pm, path, hash);
+        }
+        InstallSource::Path(path) => super::eval_package(path, trace_eval)?,
     };
 
-    dbg!(package);
+    let (tx, rx) = mpsc::channel();
+
+    match definition {
+        Definition::Package(package) => {
+            info!("Installing package \"{}\"", package.name);
+            thread::spawn(move || pm.install(package, &tx));
+        }
+        Definition::Group(group) => {
+            info!("Installing group \"{}\"", group.name);
+            thread::spawn(move || pm.install_group(group, &tx));
+        }
+    }
 
-    // info!("Installing package \"{}\"", package.name);
+    handle_events(rx)
+}
+
+fn install_archive(pm: PackageManager, path: PathBuf, hash: String) -> Result<(), Error> {
+    info!("Installing package archive \"{}\"", path.display());
 
-    // let (tx, rx) = mpsc::channel();
-    // thread::spawn(move || pm.install(package, &tx));
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || pm.install_archive(path, hash, &tx));
+
+    handle_events(rx)
+}
 
-    // while let Ok(event) = rx.recv() {
-    //     use Event as E;
-    //     use PackageManagerError as PkgError;
+fn handle_events(rx: Receiver<Event>) -> Result<(), Error> {
+    while let Ok(event) = rx.recv() {
+        use Event as E;
+        use PackageManagerError as PkgError;
 
-    //     match event {
-    //         E::AwaitingUnlock => info!("The package store is locked because of other processes using it"),
-    //         E::Unlocked => info!("Package store unlocked"),
-    //         E::AllocatingInStore => trace!("Creating directory in package store"),
-    //         E::CopySrcProgress(_copied, _total) => {
-    //             // TODO: Render a progress bar
-    //         }
+        match event {
+            E::AwaitingUnlock(Some(pid)) => info!("The package store is locked by another process (pid {pid})"),
+            E::AwaitingUnlock(None) => info!("The package store is locked because of other processes using it"),
+            E::Unlocked => info!("Package store unlocked"),
+            E::RecoveredTransaction(transaction) => info!("Recovered interrupted {transaction}"),
+            E::AllocatingInStore => trace!("Creating directory in package store"),
+            E::CopySrcProgress(_copied, _total) => {
+                // TODO: Render a progress bar
+            }
+            E::Substituting(cache) => info!("Installing prebuilt archive from \"{cache}\""),
+            E::SubstitutionFailed(cache, err) => error!("Could not install from \"{cache}\", falling back: {err}"),
+            E::BuildLog(line) => info!("{line}"),
+            E::ScratchKept(path) => info!("Kept the build's scratch directory at \"{}\"", path.display()),
+            E::RemovingDependent(..) | E::OutputDiffers(_) | E::Built(_) | E::DebugShell => {}
 
-    //         E::Error(err) => match err {
-    //             PkgError::PackageAlreadyInstalled => error!("Package already installed"),
-    //             _ => return Err(err.into()),
-    //         },
-    //     }
-    // }
+            E::Error(err) => match err {
+                PkgError::PackageAlreadyInstalled => error!("Package already installed"),
+                _ => return Err(err.into()),
+            },
+        }
+    }
 
     Ok(())
 }
@@ -0,0 +1,19 @@
+use crate::{err, error::Error};
+use libpkg::Severity;
+use std::path::PathBuf;
+
+pub fn lint(path: PathBuf, trace_eval: bool) -> Result<(), Error> {
+    let lints = super::eval_package(path.clone(), trace_eval)?.lint();
+
+    for lint in &lints {
+        println!("{}\t{}\t{}\t{}\t{}\t{}", path.display(), lint.severity, lint.code, lint.id, lint.field, lint.message);
+    }
+
+    let errors = lints.iter().filter(|lint| lint.severity == Severity::Error).count();
+
+    if errors > 0 {
+        return err!(LintFailed(errors));
+    }
+
+    Ok(())
+}
@@ -0,0 +1,14 @@
+use libpkg::{error::PackageManagerError, PackageManager};
+use prelude::logger::error;
+
+use crate::error::Error;
+
+pub fn log(pm: &PackageManager, id: impl AsRef<str>) -> Result<(), Error> {
+    match pm.build_log(id) {
+        Ok(log) => print!("{log}"),
+        Err(PackageManagerError::BuildLogNotFound) => error!("No build log found for the package"),
+        Err(err) => return Err(err.into()),
+    }
+
+    Ok(())
+}
@@ -1,3 +1,12 @@
+use crate::error::Error;
+use libpkg::{
+    error::Context,
+    package::{Definition, Package},
+    Source, TraceEvent,
+};
+use prelude::logger::info;
+use std::path::PathBuf;
+
 macro_rules! export_cmd {
     ($($name:ident),*) => {
         $(
@@ -7,4 +16,24 @@ macro_rules! export_cmd {
     };
 }
 
-export_cmd!(install, remove, init_root);
+export_cmd!(install, remove, init_root, gc, repair, log, bundle, build, lint, new, info);
+
+/// Evaluate a package file, logging a trace of the evaluation if asked to.
+fn eval_package(path: PathBuf, trace_eval: bool) -> Result<Definition, Error> {
+    let source = Source::from_path(path).context("pkg: read package file")?;
+
+    if !trace_eval {
+        return Ok(Package::eval(source)?);
+    }
+
+    let (result, trace) = Package::eval_traced(source);
+
+    for event in trace {
+        match event {
+            TraceEvent::Call { function, args } => info!("[trace] {function}({})", args.join(" ")),
+            TraceEvent::Result(value) => info!("[trace] result:\n{value}"),
+        }
+    }
+
+    Ok(result?)
+}
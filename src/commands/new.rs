@@ -0,0 +1,273 @@
+use crate::{cli::Template, err, error::Error};
+use libpkg::error::Context;
+use prelude::logger::info;
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+/// Package metadata read from a project's existing manifest.
+struct Metadata {
+    id: String,
+    version: String,
+    description: String,
+}
+
+pub fn new(dir: PathBuf, template: Template, trace_eval: bool) -> Result<(), Error> {
+    let path = dir.join("package.tl");
+
+    if path.exists() {
+        return err!(PackageFileExists);
+    }
+
+    fs::create_dir_all(&dir).context("pkg: create the package directory")?;
+
+    let dir = dir.canonicalize().context("pkg: resolve the package directory")?;
+    let contents = generate(&dir, template);
+
+    fs::write(&path, contents).context("pkg: write the package file")?;
+
+    // The package file is evaluated where it will be used, so that the files it reads are found, and removed if it doesn't evaluate.
+    if let Err(err) = super::eval_package(path.clone(), trace_eval) {
+        fs::remove_file(&path).context("pkg: remove the invalid package file")?;
+        return Err(err);
+    }
+
+    info!("Created \"{}\"", path.display());
+
+    Ok(())
+}
+
+/// Generate the package file for a project directory, filled in from the project's existing manifest if there is one.
+fn generate(dir: &Path, template: Template) -> String {
+    let read = |file: &str| fs::read_to_string(dir.join(file)).ok();
+    let mut metadata = Metadata {
+        id: dir.file_name().map(|name| name.to_string_lossy().into_owned()).unwrap_or_else(|| "package".into()),
+        version: "0.1.0".into(),
+        description: "No description".into(),
+    };
+
+    match template {
+        Template::Rust => {
+            let cargo = read("Cargo.toml").and_then(|cargo| cargo.parse::<toml::Table>().ok());
+            let package = cargo.as_ref().and_then(|cargo| cargo.get("package")?.as_table());
+
+            match package.and_then(|package| Some((package, package.get("name")?.as_str()?))) {
+                Some((package, name)) => {
+                    metadata.id = name.to_owned();
+                    cargo_template(package, &metadata)
+                }
+                // Virtual workspace manifests have no package to read.
+                None => fill(include_str!("../templates/rust.tl"), &metadata),
+            }
+        }
+        Template::C => {
+            let meson = read("meson.build");
+
+            if let Some(meson) = &meson {
+                let (name, version) = meson_project(meson);
+                metadata.id = name.unwrap_or(metadata.id);
+                metadata.version = version.unwrap_or(metadata.version);
+            }
+
+            let (build, install) = match meson {
+                Some(_) => ("meson setup build --prefix /; meson compile -C build", "with-env { DESTDIR: $env.out } { meson install -C build }"),
+                None => ("make", "with-env { DESTDIR: $env.out } { make install PREFIX=/ }"),
+            };
+
+            fill(include_str!("../templates/c.tl"), &metadata)
+                .replace("@build@", &string(build))
+                .replace("@install@", &string(install))
+        }
+        Template::Script => {
+            let json = read("package.json").and_then(|json| serde_json::from_str::<serde_json::Value>(&json).ok());
+            let script = find_script(dir, json.as_ref()).unwrap_or_else(|| metadata.id.clone());
+
+            if let Some(json) = &json {
+                metadata.id = json_string(json, "name").unwrap_or(metadata.id);
+                metadata.version = json_string(json, "version").unwrap_or(metadata.version);
+                metadata.description = json_string(json, "description").unwrap_or(metadata.description);
+            }
+
+            fill(include_str!("../templates/script.tl"), &metadata).replace("@script@", &string(&script))
+        }
+        Template::Meta => fill(include_str!("../templates/meta.tl"), &metadata),
+    }
+}
+
+/// Fill in the template that reads a Cargo project's `[package]` when the package file is evaluated.
+/// Fields inherited from the workspace, such as `version.workspace = true`, can't be read from the project's manifest, so they get placeholders instead.
+fn cargo_template(package: &toml::Table, metadata: &Metadata) -> String {
+    let inherited = |key: &str| package.get(key).is_some_and(toml::Value::is_table);
+    let version = match package.get("version") {
+        Some(toml::Value::String(_)) => "package.version".to_owned(),
+        _ => string(&metadata.version),
+    };
+
+    let description = match inherited("description") {
+        true => string(&metadata.description),
+        false => format!("maybe(package.description {})", string(&metadata.description)),
+    };
+    let authors = if inherited("authors") { "[ ]" } else { "maybe(package.authors [ ])" };
+
+    fill(include_str!("../templates/rust-cargo.tl"), metadata)
+        .replace("@cargo_version@", &version)
+        .replace("@cargo_description@", &description)
+        .replace("@cargo_authors@", authors)
+}
+
+/// Fill in the placeholders of a template.
+fn fill(template: &str, metadata: &Metadata) -> String {
+    template
+        .replace("@id@", &string(&to_id(&metadata.id)))
+        .replace("@version@", &string(&metadata.version))
+        .replace("@description@", &string(&metadata.description))
+}
+
+/// Quote a string for a `.tl` file.
+fn string(value: &str) -> String {
+    format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+/// Turn a project name into an id that follows the naming policy of `pkg lint`.
+fn to_id(name: &str) -> String {
+    let id = name.to_lowercase().chars().map(|c| if c.is_ascii_alphanumeric() { c } else { '-' }).collect::<String>();
+    let id = id.split('-').filter(|part| !part.is_empty()).collect::<Vec<_>>().join("-");
+
+    match id.chars().next() {
+        Some(c) if c.is_ascii_alphabetic() => id,
+        _ => format!("pkg-{id}").trim_end_matches('-').to_owned(),
+    }
+}
+
+/// The string value of a top-level key in a `package.json`.
+fn json_string(json: &serde_json::Value, key: &str) -> Option<String> {
+    json.get(key)?.as_str().map(ToOwned::to_owned)
+}
+
+/// Find the file name of the script to install: the `bin` or `main` of a `package.json`, or the only file in the directory starting with a shebang.
+/// A `bin` can also be an object of commands, the first one is installed.
+fn find_script(dir: &Path, json: Option<&serde_json::Value>) -> Option<String> {
+    let bin = |json: &serde_json::Value| match json.get("bin")? {
+        serde_json::Value::Object(commands) => commands.values().find_map(|path| path.as_str().map(ToOwned::to_owned)),
+        bin => bin.as_str().map(ToOwned::to_owned),
+    };
+
+    if let Some(script) = json.and_then(|json| bin(json).or_else(|| json_string(json, "main"))) {
+        return Some(script.trim_start_matches("./").to_owned());
+    }
+
+    let mut scripts = fs::read_dir(dir)
+        .ok()?
+        .filter_map(Result::ok)
+        .filter(|entry| entry.file_type().is_ok_and(|file_type| file_type.is_file()))
+        .filter(|entry| fs::read(entry.path()).is_ok_and(|contents| contents.starts_with(b"#!")))
+        .map(|entry| entry.file_name().to_string_lossy().into_owned());
+
+    match (scripts.next(), scripts.next()) {
+        (Some(script), None) => Some(script),
+        _ => None,
+    }
+}
+
+/// Find the name and version in the `project(...)` call of a `meson.build`.
+fn meson_project(meson: &str) -> (Option<String>, Option<String>) {
+    let quoted = |text: &str| {
+        let text = text.trim_start().strip_prefix('\'')?;
+        Some(text[..text.find('\'')?].to_owned())
+    };
+
+    let Some(project) = meson.find("project(").map(|start| &meson[start + "project(".len()..]) else {
+        return (None, None);
+    };
+
+    // Only the `version` keyword counts, not other keywords ending in it such as `meson_version`.
+    let version = project
+        .match_indices("version")
+        .filter(|(start, _)| !project[..*start].ends_with(|c: char| c.is_ascii_alphanumeric() || c == '_'))
+        .find_map(|(start, _)| project[start + "version".len()..].trim_start().strip_prefix(':'))
+        .and_then(quoted);
+
+    (quoted(project), version)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use libpkg::{
+        package::{Definition, Package},
+        Source,
+    };
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[test]
+    fn meson_versions_are_not_confused_with_other_keywords() {
+        let meson = "project('hello', 'c',\n  meson_version: '>=1.1',\n  version: '2.3.0',\n)\n";
+
+        assert_eq!(meson_project(meson), (Some("hello".into()), Some("2.3.0".into())));
+        assert_eq!(meson_project("project('hello', 'c', meson_version: '>=1.1')"), (Some("hello".into()), None));
+    }
+
+    #[test]
+    fn scripts_are_named_by_their_file() {
+        let dir = std::env::temp_dir().join(format!("pkg-new-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("hello_world.sh"), "#!/bin/sh\necho hello\n").unwrap();
+        fs::write(dir.join("README"), "hello").unwrap();
+
+        assert_eq!(find_script(&dir, None).as_deref(), Some("hello_world.sh"));
+        assert_eq!(find_script(&dir, Some(&serde_json::json!({ "name": "hello", "bin": "./cli.js" }))).as_deref(), Some("cli.js"));
+        assert_eq!(find_script(&dir, Some(&serde_json::json!({ "bin": { "hello": "bin/hello.js" } }))).as_deref(), Some("bin/hello.js"));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /// Generate a package file for a project with an optional manifest, and evaluate it.
+    fn eval_template(template: Template, manifest: Option<(&str, &str)>) -> Result<Definition, String> {
+        static COUNT: AtomicUsize = AtomicUsize::new(0);
+        let dir = std::env::temp_dir().join(format!("pkg-new-template-test-{}-{}", std::process::id(), COUNT.fetch_add(1, Ordering::Relaxed)));
+        fs::create_dir_all(&dir).unwrap();
+
+        if let Some((name, contents)) = manifest {
+            fs::write(dir.join(name), contents).unwrap();
+        }
+
+        let path = dir.join("package.tl");
+        fs::write(&path, generate(&dir, template)).unwrap();
+
+        let result = Package::eval(Source::from_path(path).unwrap()).map_err(|err| err.to_string());
+        fs::remove_dir_all(&dir).unwrap();
+
+        result
+    }
+
+    #[test]
+    fn every_template_evaluates() {
+        let cases = [
+            (Template::Rust, None),
+            (Template::Rust, Some(("Cargo.toml", "[package]\nname = \"hello\"\nversion = \"1.0.0\"\n"))),
+            (Template::C, None),
+            (Template::C, Some(("meson.build", "project('hello', 'c', version: '2.3.0')\n"))),
+            (Template::Script, Some(("package.json", r#"{ "name": "hello", "version": "1.2.0", "bin": { "hello": "./cli.js" } }"#))),
+            (Template::Meta, None),
+        ];
+
+        for (template, manifest) in cases {
+            let result = eval_template(template, manifest);
+
+            assert!(result.is_ok(), "{template:?} with {manifest:?}: {}", result.err().unwrap_or_default());
+        }
+    }
+
+    #[test]
+    fn cargo_names_become_ids_and_inherited_fields_get_placeholders() {
+        let cargo = "[package]\nname = \"Hello_World\"\nversion.workspace = true\ndescription.workspace = true\nauthors = [ \"Jane Doe\" ]\n";
+
+        let Ok(Definition::Package(package)) = eval_template(Template::Rust, Some(("Cargo.toml", cargo))) else {
+            panic!("expected a package");
+        };
+        assert_eq!((package.id.as_str(), package.version.as_str()), ("hello-world", "0.1.0"));
+        assert_eq!((package.description.as_str(), package.authors.as_slice()), ("No description", ["Jane Doe".to_owned()].as_slice()));
+        assert_eq!(package.expected_output, [Path::new("/bin/Hello_World")]);
+    }
+}
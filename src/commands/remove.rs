@@ -1,30 +1,36 @@
-use libpkg::{error::PackageManagerError, event::Event, PackageManager};
+use libpkg::{error::PackageManagerError, event::Event, PackageManager, RemovePolicy};
 use prelude::logger::{error, info, trace};
 use std::{sync::mpsc, thread};
 
 use crate::error::Error;
 
-pub fn remove(pm: PackageManager, id: impl ToString) -> Result<(), Error> {
+pub fn remove(pm: PackageManager, id: impl ToString, policy: RemovePolicy) -> Result<(), Error> {
     let id = id.to_string();
     info!("Removing \"{id}\"");
 
     let (tx, rx) = mpsc::channel();
-    thread::spawn(move || pm.remove(id, None, &tx));
+    thread::spawn(move || pm.remove(id, None, policy, &tx));
 
     while let Ok(event) = rx.recv() {
         use Event as E;
         use PackageManagerError as PkgError;
 
         match event {
-            E::AwaitingUnlock => info!("The package store is locked because of other processes using it"),
+            E::AwaitingUnlock(Some(pid)) => info!("The package store is locked by another process (pid {pid})"),
+            E::AwaitingUnlock(None) => info!("The package store is locked because of other processes using it"),
             E::Unlocked => info!("Package store unlocked"),
+            E::RecoveredTransaction(transaction) => info!("Recovered interrupted {transaction}"),
             E::AllocatingInStore => trace!("Creating directory in package store"),
             E::CopySrcProgress(_copied, _total) => {
                 // TODO: Render a progress bar
             }
+            E::BuildLog(line) => trace!("{line}"),
+            E::Substituting(..) | E::SubstitutionFailed(..) | E::ScratchKept(_) | E::Built(_) | E::DebugShell | E::OutputDiffers(_) => {}
+            E::RemovingDependent(id, version) => info!("Also removing dependent \"{id}@{version}\""),
 
             E::Error(err) => match err {
                 PkgError::PackageNotInstalled => error!("Package not installed"),
+                PkgError::PackageRequired(dependents) => error!("Package is still required by {}, use --cascade or --force to remove it anyway", dependents.join(", ")),
                 _ => return Err(err.into()),
             },
         }
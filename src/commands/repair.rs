@@ -0,0 +1,18 @@
+use libpkg::PackageManager;
+use prelude::logger::info;
+
+use crate::error::Error;
+
+pub fn repair(pm: &PackageManager) -> Result<(), Error> {
+    let recovered = pm.repair()?;
+
+    if recovered.is_empty() {
+        info!("Nothing to repair");
+    }
+
+    for transaction in &recovered {
+        info!("Recovered interrupted {transaction}");
+    }
+
+    Ok(())
+}
@@ -1,6 +1,6 @@
 use cli::{Cli, Command};
 use error::{err, Error};
-use libpkg::PackageManager;
+use libpkg::{BuildOptions, PackageManager, RemovePolicy};
 
 mod cli;
 mod commands;
@@ -15,6 +15,20 @@ mod error {
         CorruptedRoot,
         #[error("The root that was given is already initialized.")]
         AlreadyInitialized,
+        #[error("The given file defines a group, only single packages can be built.")]
+        GroupNotBuildable,
+        #[error("The directory already has a package file.")]
+        PackageFileExists,
+        #[error("The package file has {0} lint errors.")]
+        LintFailed(usize),
+        #[error("The package is not installed, only installed packages can be checked.")]
+        CheckNotInstalled,
+        #[error("\"{0}\" is neither a package file nor an installed package.")]
+        InfoNotInstalled(String),
+        #[error("Archives can only be installed with the output hash that `pkg bundle` printed, pass it with --hash.")]
+        ArchiveHashRequired,
+        #[error("The rebuilt package differs from the installed one in {0} paths.")]
+        NotReproducible(usize),
 
         #[error("{0}")]
         PkgError(#[from] PackageManagerError),
@@ -36,8 +50,38 @@ fn main(args: Cli) {
     }
 
     match args.command {
-        Command::Install { source } => commands::install(pm, source),
-        Command::Remove { id } => commands::remove(pm, id),
+        Command::Install { source, hash } => commands::install(pm, source, hash, args.trace_eval),
+        Command::Remove { id, cascade, force } => {
+            let policy = match (cascade, force) {
+                (true, _) => RemovePolicy::Cascade,
+                (_, true) => RemovePolicy::Force,
+                _ => RemovePolicy::Refuse,
+            };
+
+            commands::remove(pm, id, policy)
+        }
         Command::InitRoot => commands::init_root(&pm),
+        Command::Gc { dry_run } => commands::gc(&pm, dry_run),
+        Command::Repair => commands::repair(&pm),
+        Command::Log { id } => commands::log(&pm, id),
+        Command::Bundle { id, output } => commands::bundle(&pm, id, output),
+        Command::Info { source } => commands::info(&pm, source, args.trace_eval),
+        Command::Lint { path } => commands::lint(path, args.trace_eval),
+        Command::New { dir, template } => commands::new(dir, template, args.trace_eval),
+        Command::Build {
+            path,
+            check,
+            output,
+            keep_sandbox,
+            shell_on_failure,
+        } => {
+            let options = BuildOptions {
+                output,
+                keep_sandbox,
+                shell_on_failure,
+            };
+
+            commands::build(pm, path, check, options, args.trace_eval)
+        }
     }
 }